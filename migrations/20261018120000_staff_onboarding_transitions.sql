@@ -0,0 +1,18 @@
+-- History of every state change (and creation/void/deletion) of an onboarding
+--
+-- There is intentionally no foreign key to staff_onboardings as the history
+-- must survive the onboarding being deleted
+CREATE TABLE IF NOT EXISTS staff_onboarding_transitions (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    onboarding_id UUID NOT NULL,
+    user_id TEXT NOT NULL,
+    from_state TEXT,
+    to_state TEXT,
+    event TEXT NOT NULL,
+    actor TEXT NOT NULL,
+    metadata JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS staff_onboarding_transitions_user_id_idx ON staff_onboarding_transitions (user_id, created_at);
+CREATE INDEX IF NOT EXISTS staff_onboarding_transitions_onboarding_id_idx ON staff_onboarding_transitions (onboarding_id, created_at);
@@ -0,0 +1,2 @@
+-- Automatically graded multiple choice score breakdown of an onboarding
+ALTER TABLE staff_onboardings ADD COLUMN IF NOT EXISTS score JSONB;
@@ -0,0 +1,2 @@
+-- Manual (rubric based) grades of the short and long answers of an onboarding
+ALTER TABLE staff_onboardings ADD COLUMN IF NOT EXISTS grades JSONB;
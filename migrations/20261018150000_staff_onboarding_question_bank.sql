@@ -0,0 +1,20 @@
+-- Question bank for onboarding quizzes. Questions are never edited in place, every
+-- edit creates a new revision so onboardings can resolve the exact revision they asked
+CREATE TABLE IF NOT EXISTS staff_onboarding_questions (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    -- Latest revision of the question
+    revision INTEGER NOT NULL DEFAULT 1,
+    -- Deleted questions are deactivated so old onboardings can still resolve them
+    active BOOLEAN NOT NULL DEFAULT true,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS staff_onboarding_question_revisions (
+    question_id UUID NOT NULL REFERENCES staff_onboarding_questions (id) ON DELETE CASCADE,
+    revision INTEGER NOT NULL,
+    -- The question itself, in the same format as ``questions`` in config.yaml
+    data JSONB NOT NULL,
+    created_by TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (question_id, revision)
+);
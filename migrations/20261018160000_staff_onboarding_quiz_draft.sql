@@ -0,0 +1,2 @@
+-- Partially filled in quiz answers, saved by the panel while the quiz is being taken
+ALTER TABLE staff_onboardings ADD COLUMN IF NOT EXISTS quiz_draft JSONB;
@@ -0,0 +1,5 @@
+-- When the current quiz was created, used to enforce the quiz time limit
+ALTER TABLE staff_onboardings ADD COLUMN IF NOT EXISTS quiz_started_at TIMESTAMPTZ;
+
+-- Timings of the submitted quiz (see QuizTimings)
+ALTER TABLE staff_onboardings ADD COLUMN IF NOT EXISTS quiz_timings JSONB;
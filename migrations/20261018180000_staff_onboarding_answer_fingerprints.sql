@@ -0,0 +1,14 @@
+-- MinHash signatures of submitted short and long answers, used to find near-identical answers
+CREATE TABLE IF NOT EXISTS staff_onboarding_answer_fingerprints (
+    onboarding_id UUID NOT NULL,
+    user_id TEXT NOT NULL,
+    -- Question bank ID of the question, or the question itself for questions not in the bank
+    question TEXT NOT NULL,
+    signature BIGINT[] NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS staff_onboarding_answer_fingerprints_question_idx ON staff_onboarding_answer_fingerprints (question);
+
+-- Most similar previous answers found on submission (see SimilarityReport)
+ALTER TABLE staff_onboardings ADD COLUMN IF NOT EXISTS similarity JSONB;
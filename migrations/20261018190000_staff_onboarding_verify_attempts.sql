@@ -0,0 +1,5 @@
+-- Incorrect staff verification codes inputted since the last lockout or correct code
+ALTER TABLE staff_onboardings ADD COLUMN IF NOT EXISTS staff_verify_attempts INTEGER NOT NULL DEFAULT 0;
+
+-- Staff verification codes are not accepted until this time
+ALTER TABLE staff_onboardings ADD COLUMN IF NOT EXISTS staff_verify_locked_until TIMESTAMPTZ;
@@ -0,0 +1,11 @@
+-- Session management for persepolis logins
+ALTER TABLE staffpanel__authchain ADD COLUMN IF NOT EXISTS session_id UUID NOT NULL DEFAULT gen_random_uuid();
+
+-- When the session token expires, sessions created before this column expire an hour after creation
+ALTER TABLE staffpanel__authchain ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ;
+
+-- Used to get a new session token once it expires
+ALTER TABLE staffpanel__authchain ADD COLUMN IF NOT EXISTS refresh_token TEXT;
+ALTER TABLE staffpanel__authchain ADD COLUMN IF NOT EXISTS refresh_expires_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS staffpanel__authchain_refresh_token_idx ON staffpanel__authchain (refresh_token);
@@ -0,0 +1,10 @@
+-- Persepolis sessions store a keyed hash of their tokens instead of the tokens themselves.
+-- Existing sessions are hashed on startup (see server::auth::hash_legacy_sessions) as the key
+-- is only known to persepolis
+ALTER TABLE staffpanel__authchain ADD COLUMN IF NOT EXISTS token_hash TEXT;
+ALTER TABLE staffpanel__authchain ADD COLUMN IF NOT EXISTS refresh_token_hash TEXT;
+
+CREATE UNIQUE INDEX IF NOT EXISTS staffpanel__authchain_token_hash_idx ON staffpanel__authchain (token_hash);
+CREATE UNIQUE INDEX IF NOT EXISTS staffpanel__authchain_refresh_token_hash_idx ON staffpanel__authchain (refresh_token_hash);
+
+DROP INDEX IF EXISTS staffpanel__authchain_refresh_token_idx;
@@ -0,0 +1,3 @@
+-- Expired onboardings are now kept with the 'expired' state instead of being deleted,
+-- so index the columns the cleanup task filters on as the table keeps growing
+CREATE INDEX IF NOT EXISTS staff_onboardings_state_created_at_idx ON staff_onboardings (state, created_at);
@@ -1,10 +1,11 @@
 use crate::{checks, Context, Error};
 use botox::{cache::{CacheHttpImpl, member_on_guild}, crypto::gen_random};
 use poise::{
-    serenity_prelude::{ButtonStyle, CreateActionRow, CreateButton, CreateMessage, GuildId, User},
+    serenity_prelude::{ButtonStyle, CreateActionRow, CreateAttachment, CreateButton, CreateMessage, GuildId, User},
     CreateReply,
 };
 use serenity::builder::{CreateInvite, EditMessage};
+use sqlx::types::chrono::{self, DateTime, NaiveDate, Utc};
 
 /// Guild base command
 #[poise::command(
@@ -93,7 +94,7 @@ pub async fn staff_guildleave(
     prefix_command,
     slash_command,
     guild_cooldown = 10,
-    subcommands("approveonboard", "denyonboard", "resetonboard",)
+    subcommands("approveonboard", "denyonboard", "resetonboard", "timelineonboard", "revokesessions", "report", "history",)
 )]
 pub async fn admin(_ctx: Context<'_>) -> Result<(), Error> {
     Ok(())
@@ -111,7 +112,8 @@ pub async fn admin(_ctx: Context<'_>) -> Result<(), Error> {
 pub async fn approveonboard(
     ctx: Context<'_>,
     #[description = "The staff id"] member: User,
-    #[description = "Whether or not to force approve. Not recommended unless required"] force: Option<bool>,
+    #[description = "Force approve even if there is no onboarding pending review. Not recommended unless required"] force: Option<bool>,
+    #[description = "Whether to approve even if grading is not complete. Not recommended unless required"] skip_grading: Option<bool>,
 ) -> Result<(), Error> {
     ctx.defer().await?;
 
@@ -121,7 +123,7 @@ pub async fn approveonboard(
 
     // Check onboard state of user
     let onboard_state = sqlx::query!(
-        "SELECT id, guild_id FROM staff_onboardings WHERE user_id = $1 AND state = $2 AND NOW() - created_at < INTERVAL '3 hours' ORDER BY created_at DESC LIMIT 1",
+        "SELECT id, guild_id, questions, grades FROM staff_onboardings WHERE user_id = $1 AND state = $2 AND NOW() - created_at < INTERVAL '3 hours' ORDER BY created_at DESC LIMIT 1",
         member.id.to_string(),
         crate::states::OnboardState::PendingManagerReview.to_string()
     )
@@ -132,12 +134,20 @@ pub async fn approveonboard(
 
     if let Some(onboard_state) = onboard_state {
         // Update onboard state of user
-        sqlx::query!(
-            "UPDATE staff_onboardings SET state = $1 WHERE id = $2",
-            crate::states::OnboardState::Completed.to_string(),
-            onboard_state.id
+        crate::states::transition(
+            &mut *tx,
+            onboard_state.id,
+            crate::states::OnboardState::PendingManagerReview,
+            crate::states::OnboardEvent::ManagerApprove,
+            &crate::states::TransitionContext {
+                skip_grading: skip_grading.unwrap_or(false),
+                grading_complete: crate::quiz::is_grading_complete(
+                    onboard_state.questions,
+                    onboard_state.grades,
+                )?,
+                ..crate::states::TransitionContext::new(&ctx.author().id.to_string())
+            },
         )
-        .execute(&mut *tx)
         .await?;
 
         tx.commit().await?;
@@ -150,13 +160,34 @@ pub async fn approveonboard(
             return Err("User does not have any onboardings pending manager review".into());
         }
 
-        sqlx::query!(
-            "INSERT INTO staff_onboardings (user_id, guild_id, state) VALUES ($1, $2, $3)",
+        // A forced verdict lands in the same state a reviewed one would
+        let to = crate::states::lookup(
+            crate::states::OnboardState::PendingManagerReview,
+            crate::states::OnboardEvent::ManagerApprove
+        )?
+        .to;
+
+        let rec = sqlx::query!(
+            "INSERT INTO staff_onboardings (user_id, guild_id, state) VALUES ($1, $2, $3) RETURNING id",
             member.id.to_string(),
             "force_approved".to_string() + &gen_random(12),
-            crate::states::OnboardState::Completed.to_string(),
+            to.to_string(),
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        crate::history::record(
+            &mut *tx,
+            crate::history::HistoryEntry {
+                onboarding_id: rec.id,
+                user_id: &member.id.to_string(),
+                from: None,
+                to: Some(to),
+                event: crate::states::OnboardEvent::ManagerApprove,
+                actor: &ctx.author().id.to_string(),
+                metadata: serde_json::json!({ "forced": true }),
+            },
         )
-        .execute(&mut *tx)
         .await?;
 
         tx.commit().await?;
@@ -224,7 +255,8 @@ pub async fn approveonboard(
 pub async fn denyonboard(
     ctx: crate::Context<'_>,
     #[description = "The staff id"] user: User,
-    #[description = "Whether or not to force deny. Not recommended unless required"] force: Option<bool>,
+    #[description = "Force deny even if there is no onboarding pending review. Not recommended unless required"] force: Option<bool>,
+    #[description = "Whether to deny even if grading is not complete. Not recommended unless required"] skip_grading: Option<bool>,
 ) -> Result<(), Error> {
     ctx.defer().await?;
 
@@ -234,7 +266,7 @@ pub async fn denyonboard(
 
     // Check onboard state of user
     let onboard_state = sqlx::query!(
-        "SELECT id, guild_id FROM staff_onboardings WHERE user_id = $1 AND state = $2 AND NOW() - created_at < INTERVAL '3 hours' ORDER BY created_at DESC LIMIT 1",
+        "SELECT id, guild_id, questions, grades FROM staff_onboardings WHERE user_id = $1 AND state = $2 AND NOW() - created_at < INTERVAL '3 hours' ORDER BY created_at DESC LIMIT 1",
         user.id.to_string(),
         crate::states::OnboardState::PendingManagerReview.to_string()
     )
@@ -245,12 +277,20 @@ pub async fn denyonboard(
 
     if let Some(onboard_state) = onboard_state {
         // Update onboard state of user
-        sqlx::query!(
-            "UPDATE staff_onboardings SET state = $1 WHERE id = $2",
-            crate::states::OnboardState::Completed.to_string(),
-            onboard_state.id
+        crate::states::transition(
+            &mut *tx,
+            onboard_state.id,
+            crate::states::OnboardState::PendingManagerReview,
+            crate::states::OnboardEvent::ManagerDeny,
+            &crate::states::TransitionContext {
+                skip_grading: skip_grading.unwrap_or(false),
+                grading_complete: crate::quiz::is_grading_complete(
+                    onboard_state.questions,
+                    onboard_state.grades,
+                )?,
+                ..crate::states::TransitionContext::new(&ctx.author().id.to_string())
+            },
         )
-        .execute(&mut *tx)
         .await?;
 
         tx.commit().await?;
@@ -263,13 +303,34 @@ pub async fn denyonboard(
             return Err("User does not have any onboardings pending manager review".into());
         }
 
-        sqlx::query!(
-            "INSERT INTO staff_onboardings (user_id, guild_id, state) VALUES ($1, $2, $3)",
+        // A forced verdict lands in the same state a reviewed one would
+        let to = crate::states::lookup(
+            crate::states::OnboardState::PendingManagerReview,
+            crate::states::OnboardEvent::ManagerDeny
+        )?
+        .to;
+
+        let rec = sqlx::query!(
+            "INSERT INTO staff_onboardings (user_id, guild_id, state) VALUES ($1, $2, $3) RETURNING id",
             user.id.to_string(),
             "force_approved".to_string() + &gen_random(12),
-            crate::states::OnboardState::Denied.to_string(),
+            to.to_string(),
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        crate::history::record(
+            &mut *tx,
+            crate::history::HistoryEntry {
+                onboarding_id: rec.id,
+                user_id: &user.id.to_string(),
+                from: None,
+                to: Some(to),
+                event: crate::states::OnboardEvent::ManagerDeny,
+                actor: &ctx.author().id.to_string(),
+                metadata: serde_json::json!({ "forced": true }),
+            },
         )
-        .execute(&mut *tx)
         .await?;
 
         tx.commit().await?;
@@ -333,14 +394,36 @@ pub async fn resetonboard(
         return Ok(());
     }
 
+    let mut tx = data.pool.begin().await?;
+
     // Update onboard state of a user
-    sqlx::query!(
-        "UPDATE staff_onboardings SET void = true WHERE user_id = $1",
+    let voided = sqlx::query!(
+        "UPDATE staff_onboardings SET void = true WHERE user_id = $1 AND void = false RETURNING id, state",
         user.id.to_string()
     )
-    .execute(&data.pool)
+    .fetch_all(&mut *tx)
     .await?;
 
+    for rec in voided {
+        let state = rec.state.parse::<crate::states::OnboardState>()?;
+
+        crate::history::record(
+            &mut *tx,
+            crate::history::HistoryEntry {
+                onboarding_id: rec.id,
+                user_id: &user.id.to_string(),
+                from: Some(state),
+                to: Some(state),
+                event: crate::states::OnboardEvent::Void,
+                actor: &ctx.author().id.to_string(),
+                metadata: serde_json::json!({}),
+            },
+        )
+        .await?;
+    }
+
+    tx.commit().await?;
+
     // DM user that they have been force reset
     let _ = user.dm(&ctx.serenity_context().http, CreateMessage::new().content("Your onboarding request has been force reset. Please contact a manager for more information. You will, in most cases, need to redo onboarding")).await?;
 
@@ -348,3 +431,225 @@ pub async fn resetonboard(
 
     Ok(())
 }
+
+/// Shows the timeline of all onboardings of a user
+#[poise::command(
+    rename = "timeline",
+    category = "Admin",
+    track_edits,
+    prefix_command,
+    slash_command,
+    check = "checks::is_admin"
+)]
+pub async fn timelineonboard(
+    ctx: crate::Context<'_>,
+    #[description = "The staff id"] user: User,
+) -> Result<(), Error> {
+    let timeline = crate::history::get_timeline(&ctx.data().pool, &user.id.to_string(), None).await?;
+
+    if timeline.is_empty() {
+        ctx.say("This user has no onboarding history").await?;
+        return Ok(());
+    }
+
+    let mut msg = String::new();
+
+    for entry in timeline {
+        let actor = if entry.actor == crate::history::SYSTEM_ACTOR {
+            entry.actor
+        } else {
+            format!("<@{}>", entry.actor)
+        };
+
+        let line = format!(
+            "<t:{}:f> `{}` **{} -> {}** (`{}` by {}){}\n",
+            entry.created_at,
+            entry.onboarding_id.chars().take(8).collect::<String>(),
+            entry.from_state.as_deref().unwrap_or("none"),
+            entry.to_state.as_deref().unwrap_or("none"),
+            entry.event,
+            actor,
+            entry
+                .time_in_from_state
+                .map(|secs| format!(" after {}", format_duration(secs)))
+                .unwrap_or_default()
+        );
+
+        // Discord messages are limited to 2000 characters
+        if msg.len() + line.len() > 2000 {
+            ctx.say(&msg).await?;
+            msg.clear();
+        }
+
+        msg.push_str(&line);
+    }
+
+    ctx.say(msg).await?;
+
+    Ok(())
+}
+
+/// Logs a user out of every persepolis session
+#[poise::command(
+    rename = "revokesessions",
+    category = "Admin",
+    track_edits,
+    prefix_command,
+    slash_command,
+    check = "checks::is_admin"
+)]
+pub async fn revokesessions(
+    ctx: crate::Context<'_>,
+    #[description = "The staff id"] user: User,
+) -> Result<(), Error> {
+    let count = crate::server::auth::revoke_user_sessions(&ctx.data().pool, &user.id.to_string()).await?;
+
+    ctx.say(format!("Revoked {} sessions of {}", count, user.name)).await?;
+
+    Ok(())
+}
+
+/// Outcomes of onboardings over the last 7 and 30 days and the most recent expirations
+#[poise::command(
+    rename = "history",
+    category = "Admin",
+    track_edits,
+    prefix_command,
+    slash_command,
+    check = "checks::is_admin"
+)]
+pub async fn history(ctx: crate::Context<'_>) -> Result<(), Error> {
+    let pool = &ctx.data().pool;
+
+    let mut msg = String::from("**Onboarding history** (last 7 days / last 30 days)\n");
+
+    for state in [
+        crate::states::OnboardState::Completed,
+        crate::states::OnboardState::Denied,
+        crate::states::OnboardState::Expired,
+    ] {
+        let (week, month) = crate::history::recent_transitions_to(pool, state).await?;
+
+        msg.push_str(&format!("`{}`: {} / {}\n", state, week, month));
+    }
+
+    let expirations = crate::history::recent_expirations(pool, 10).await?;
+
+    if !expirations.is_empty() {
+        msg.push_str("\n**Recent expirations**\n");
+
+        for expiration in expirations {
+            msg.push_str(&format!(
+                "<t:{}:f> `{}` <@{}> in `{}` ({})\n",
+                expiration.created_at,
+                expiration
+                    .onboarding_id
+                    .hyphenated()
+                    .to_string()
+                    .chars()
+                    .take(8)
+                    .collect::<String>(),
+                expiration.user_id,
+                expiration.from_state.as_deref().unwrap_or("unknown"),
+                expiration.reason.as_deref().unwrap_or("no reason")
+            ));
+        }
+    }
+
+    ctx.say(msg).await?;
+
+    Ok(())
+}
+
+/// Parses a ``YYYY-MM-DD`` date as midnight UTC
+fn parse_date(date: &str) -> Result<DateTime<Utc>, Error> {
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|_| format!("Invalid date `{}`, expected YYYY-MM-DD", date))?;
+
+    Ok(date.and_hms_opt(0, 0, 0).ok_or("Invalid date")?.and_utc())
+}
+
+/// Onboarding analytics of a date range, with a CSV export
+#[poise::command(
+    rename = "report",
+    category = "Admin",
+    track_edits,
+    prefix_command,
+    slash_command,
+    check = "checks::is_admin"
+)]
+pub async fn report(
+    ctx: crate::Context<'_>,
+    #[description = "Start date (YYYY-MM-DD), defaults to 30 days ago"] from: Option<String>,
+    #[description = "End date (YYYY-MM-DD), exclusive, defaults to now"] to: Option<String>,
+) -> Result<(), Error> {
+    let to = match to {
+        Some(to) => parse_date(&to)?,
+        None => Utc::now(),
+    };
+
+    let from = match from {
+        Some(from) => parse_date(&from)?,
+        None => to - chrono::Duration::days(30),
+    };
+
+    if from >= to {
+        return Err("The start date must be before the end date".into());
+    }
+
+    let report = crate::analytics::report(&ctx.data().pool, from, to).await?;
+
+    let percent = |v: Option<f64>| {
+        v.map(|v| format!("{:.1}%", v * 100.0))
+            .unwrap_or_else(|| "n/a".to_string())
+    };
+
+    let mut msg = format!(
+        "**Onboarding report** <t:{}:d> to <t:{}:d>\n\n{} created, {} denied, {} expired\nApproval rate: {}, denial rate: {}\n\n**Funnel**\n",
+        report.from,
+        report.to,
+        report.created,
+        report.denied,
+        report.expired,
+        percent(report.approval_rate),
+        percent(report.denial_rate)
+    );
+
+    for stage in &report.stages {
+        msg.push_str(&format!(
+            "`{}`: {} ({:.1}%){}\n",
+            stage.stage,
+            stage.reached,
+            stage.conversion * 100.0,
+            stage
+                .median_secs
+                .map(|secs| format!(", median {}", format_duration(secs as i64)))
+                .unwrap_or_default()
+        ));
+    }
+
+    msg.push_str("\nPer question accuracy is in the attached CSV");
+
+    ctx.send(
+        CreateReply::default().content(msg).attachment(CreateAttachment::bytes(
+            report.to_csv().into_bytes(),
+            "onboarding-report.csv",
+        )),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Formats a duration in seconds as ``1h 2m 3s``
+fn format_duration(secs: i64) -> String {
+    let (hours, mins, secs) = (secs / 3600, (secs % 3600) / 60, secs % 60);
+
+    if hours > 0 {
+        format!("{}h {}m {}s", hours, mins, secs)
+    } else if mins > 0 {
+        format!("{}m {}s", mins, secs)
+    } else {
+        format!("{}s", secs)
+    }
+}
@@ -0,0 +1,371 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    PgPool,
+};
+use ts_rs::TS;
+
+use crate::states::{OnboardEvent, OnboardState};
+use crate::Error;
+
+/// Stages of the onboarding funnel, in the order trainees go through them
+///
+/// ``QueueRemindedReviewer`` is optional so fewer onboardings may reach it than ``Claimed``
+pub const FUNNEL: [OnboardState; 7] = [
+    OnboardState::Pending,
+    OnboardState::Started,
+    OnboardState::QueueRemindedReviewer,
+    OnboardState::Claimed,
+    OnboardState::InQuiz,
+    OnboardState::PendingManagerReview,
+    OnboardState::Completed,
+];
+
+/// Reason the cleanup task expires onboardings with
+pub const EXPIRED_REASON: &str = "expired";
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/StageStats.ts")]
+pub struct StageStats {
+    pub stage: String,
+    /// Onboardings of the cohort that reached this stage
+    pub reached: i64,
+    /// ``reached`` over the number of onboardings created
+    pub conversion: f64,
+    /// Median seconds spent in this stage before moving on, if any onboarding left it
+    pub median_secs: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/QuestionAccuracy.ts")]
+pub struct QuestionAccuracy {
+    pub question: String,
+    pub answered: i64,
+    pub correct: i64,
+    pub accuracy: f64,
+}
+
+/// Analytics of the onboardings created in a date range
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/OnboardingReport.ts")]
+pub struct Report {
+    pub from: i64,
+    pub to: i64,
+    /// Onboardings created in the range
+    pub created: i64,
+    pub stages: Vec<StageStats>,
+    pub denied: i64,
+    /// Onboardings that expired before being submitted
+    pub expired: i64,
+    /// Completed over completed and denied, ``None`` if no onboarding was decided yet
+    pub approval_rate: Option<f64>,
+    pub denial_rate: Option<f64>,
+    /// Accuracy of every automatically graded multiple choice question
+    pub questions: Vec<QuestionAccuracy>,
+}
+
+fn ratio(part: i64, total: i64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 / total as f64
+    }
+}
+
+/// Aggregates the onboardings created between ``from`` and ``to``
+///
+/// Onboardings are followed as a cohort, so later transitions of onboardings created in the range
+/// are counted even if they happened after ``to``
+pub async fn report(
+    pool: &PgPool,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Report, Error> {
+    let create_event = OnboardEvent::Create.to_string();
+
+    let created = sqlx::query!(
+        "SELECT COUNT(*) AS count FROM staff_onboarding_transitions WHERE event = $1 AND created_at >= $2 AND created_at < $3",
+        create_event,
+        from,
+        to
+    )
+    .fetch_one(pool)
+    .await?
+    .count
+    .unwrap_or_default();
+
+    let reached = sqlx::query!(
+        "
+WITH cohort AS (
+    SELECT onboarding_id FROM staff_onboarding_transitions
+    WHERE event = $1 AND created_at >= $2 AND created_at < $3
+)
+SELECT to_state AS \"state!\", COUNT(DISTINCT onboarding_id) AS count
+FROM staff_onboarding_transitions
+WHERE onboarding_id IN (SELECT onboarding_id FROM cohort) AND to_state IS NOT NULL
+GROUP BY to_state
+        ",
+        create_event,
+        from,
+        to
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let medians = sqlx::query!(
+        "
+WITH cohort AS (
+    SELECT onboarding_id FROM staff_onboarding_transitions
+    WHERE event = $1 AND created_at >= $2 AND created_at < $3
+), stages AS (
+    SELECT from_state, created_at - LAG(created_at) OVER (PARTITION BY onboarding_id ORDER BY created_at) AS time_in_stage
+    FROM staff_onboarding_transitions
+    WHERE onboarding_id IN (SELECT onboarding_id FROM cohort)
+)
+SELECT from_state AS \"state!\", percentile_cont(0.5) WITHIN GROUP (ORDER BY EXTRACT(EPOCH FROM time_in_stage)) AS median_secs
+FROM stages
+WHERE from_state IS NOT NULL AND time_in_stage IS NOT NULL
+GROUP BY from_state
+        ",
+        create_event,
+        from,
+        to
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let expired = sqlx::query!(
+        "
+WITH cohort AS (
+    SELECT onboarding_id FROM staff_onboarding_transitions
+    WHERE event = $1 AND created_at >= $2 AND created_at < $3
+)
+SELECT COUNT(DISTINCT onboarding_id) AS count
+FROM staff_onboarding_transitions
+WHERE onboarding_id IN (SELECT onboarding_id FROM cohort)
+AND to_state = $4
+        ",
+        create_event,
+        from,
+        to,
+        OnboardState::Expired.to_string()
+    )
+    .fetch_one(pool)
+    .await?
+    .count
+    .unwrap_or_default();
+
+    let questions = sqlx::query!(
+        "
+SELECT q->>'question' AS \"question!\", COUNT(*) AS answered, COUNT(*) FILTER (WHERE (q->>'correct')::BOOLEAN) AS correct
+FROM staff_onboardings o, jsonb_array_elements(o.score->'questions') q
+WHERE o.score IS NOT NULL AND o.created_at >= $1 AND o.created_at < $2
+GROUP BY q->>'question'
+ORDER BY q->>'question'
+        ",
+        from,
+        to
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let reached_count = |state: OnboardState| {
+        reached
+            .iter()
+            .find(|r| r.state == state.to_string())
+            .and_then(|r| r.count)
+            .unwrap_or_default()
+    };
+
+    let stages = FUNNEL
+        .iter()
+        .map(|state| {
+            let reached = reached_count(*state);
+
+            StageStats {
+                stage: state.to_string(),
+                reached,
+                conversion: ratio(reached, created),
+                median_secs: medians
+                    .iter()
+                    .find(|m| m.state == state.to_string())
+                    .and_then(|m| m.median_secs),
+            }
+        })
+        .collect();
+
+    let completed = reached_count(OnboardState::Completed);
+    let denied = reached_count(OnboardState::Denied);
+    let decided = completed + denied;
+
+    Ok(Report {
+        from: from.timestamp(),
+        to: to.timestamp(),
+        created,
+        stages,
+        denied,
+        expired,
+        approval_rate: (decided > 0).then(|| ratio(completed, decided)),
+        denial_rate: (decided > 0).then(|| ratio(denied, decided)),
+        questions: questions
+            .into_iter()
+            .map(|q| {
+                let answered = q.answered.unwrap_or_default();
+                let correct = q.correct.unwrap_or_default();
+
+                QuestionAccuracy {
+                    question: q.question,
+                    answered,
+                    correct,
+                    accuracy: ratio(correct, answered),
+                }
+            })
+            .collect(),
+    })
+}
+
+/// Quotes a CSV field if needed
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+impl Report {
+    /// Renders the report as CSV, with the summary, stages and questions as separate tables
+    pub fn to_csv(&self) -> String {
+        let optional = |v: Option<f64>| v.map(|v| format!("{:.4}", v)).unwrap_or_default();
+
+        let mut csv = String::from("metric,value\n");
+
+        csv.push_str(&format!("from,{}\n", self.from));
+        csv.push_str(&format!("to,{}\n", self.to));
+        csv.push_str(&format!("created,{}\n", self.created));
+        csv.push_str(&format!("denied,{}\n", self.denied));
+        csv.push_str(&format!("expired,{}\n", self.expired));
+        csv.push_str(&format!("approval_rate,{}\n", optional(self.approval_rate)));
+        csv.push_str(&format!("denial_rate,{}\n", optional(self.denial_rate)));
+
+        csv.push_str("\nstage,reached,conversion,median_secs\n");
+
+        for stage in &self.stages {
+            csv.push_str(&format!(
+                "{},{},{:.4},{}\n",
+                stage.stage,
+                stage.reached,
+                stage.conversion,
+                optional(stage.median_secs)
+            ));
+        }
+
+        csv.push_str("\nquestion,answered,correct,accuracy\n");
+
+        for question in &self.questions {
+            csv.push_str(&format!(
+                "{},{},{},{:.4}\n",
+                csv_field(&question.question),
+                question.answered,
+                question.correct,
+                question.accuracy
+            ));
+        }
+
+        csv
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> Report {
+        Report {
+            from: 1700000000,
+            to: 1700086400,
+            created: 3,
+            stages: vec![
+                StageStats {
+                    stage: OnboardState::Pending.to_string(),
+                    reached: 3,
+                    conversion: 1.0,
+                    median_secs: Some(12.5),
+                },
+                StageStats {
+                    stage: OnboardState::Completed.to_string(),
+                    reached: 1,
+                    conversion: ratio(1, 3),
+                    median_secs: None,
+                },
+            ],
+            denied: 0,
+            expired: 2,
+            approval_rate: Some(1.0),
+            denial_rate: None,
+            questions: vec![
+                QuestionAccuracy {
+                    question: "What is a bot?".to_string(),
+                    answered: 2,
+                    correct: 1,
+                    accuracy: 0.5,
+                },
+                QuestionAccuracy {
+                    question: "Pick one, \"any\"\nor none".to_string(),
+                    answered: 0,
+                    correct: 0,
+                    accuracy: ratio(0, 0),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn ratios_of_nothing_are_zero() {
+        assert_eq!(ratio(0, 0), 0.0);
+        assert_eq!(ratio(5, 0), 0.0);
+        assert_eq!(ratio(1, 4), 0.25);
+        assert_eq!(ratio(4, 4), 1.0);
+    }
+
+    #[test]
+    fn plain_fields_are_not_quoted() {
+        assert_eq!(csv_field("What is a bot?"), "What is a bot?");
+        assert_eq!(csv_field(""), "");
+    }
+
+    #[test]
+    fn special_fields_are_quoted_and_escaped() {
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("one\ntwo"), "\"one\ntwo\"");
+        assert_eq!(csv_field("one\r\ntwo"), "\"one\r\ntwo\"");
+        assert_eq!(csv_field("\""), "\"\"\"\"");
+    }
+
+    #[test]
+    fn reports_render_as_csv() {
+        assert_eq!(
+            report().to_csv(),
+            "metric,value
+from,1700000000
+to,1700086400
+created,3
+denied,0
+expired,2
+approval_rate,1.0000
+denial_rate,
+
+stage,reached,conversion,median_secs
+pending,3,1.0000,12.5000
+completed,1,0.3333,
+
+question,answered,correct,accuracy
+What is a bot?,2,1,0.5000
+\"Pick one, \"\"any\"\"
+or none\",0,0,0.0000
+"
+        );
+    }
+}
@@ -8,7 +8,7 @@ use sqlx::types::chrono;
 
 use crate::{
     config,
-    setup::{delete_or_leave_guild, setup_guild, setup_readme},
+    setup::{delete_or_leave_guild, setup_guild},
     states, Context, Error, server::types::login::ConfirmLoginState,
 };
 
@@ -65,8 +65,9 @@ pub async fn is_onboardable(ctx: Context<'_>) -> Result<bool, Error> {
 pub async fn setup_onboarding(ctx: Context<'_>) -> Result<bool, Error> {
     // Check f: sqlx::Transaction<'_, sqlx::Postgres>or an existing onboarding session
     let state = sqlx::query!(
-        "SELECT state, created_at, guild_id FROM staff_onboardings WHERE user_id = $1 AND void = false AND NOW() - created_at < INTERVAL '3 months' ORDER BY created_at DESC LIMIT 1",
-        ctx.author().id.to_string()
+        "SELECT id, state, created_at, guild_id FROM staff_onboardings WHERE user_id = $1 AND void = false AND state != $2 AND NOW() - created_at < INTERVAL '3 months' ORDER BY created_at DESC LIMIT 1",
+        ctx.author().id.to_string(),
+        states::OnboardState::Expired.to_string()
     )
     .fetch_optional(&ctx.data().pool)
     .await?;
@@ -105,13 +106,16 @@ pub async fn setup_onboarding(ctx: Context<'_>) -> Result<bool, Error> {
 
 If you accidentally left the onboarding server, you can rejoin using {}
                 ", 
-                    ConfirmLoginState::JoinOnboardingServer(ctx.author().id).make_login_url(&ctx.cache().current_user().id.to_string()),
+                    ConfirmLoginState::JoinOnboardingServer(ctx.author().id).create_login_url(),
                 ).into()
             )
         },
         _ => {}
     }
 
+    // Denied onboardings keep their verdict, the trainee just starts over in a new onboarding
+    let can_expire = states::lookup(onboard_state, states::OnboardEvent::Expire).is_ok();
+
     // Check if older than 3 hours
     if state.created_at.timestamp() + 60*60*3 < chrono::Utc::now().timestamp() {
         // They need to redo onboarding again... wipe their old progress and restart
@@ -135,22 +139,24 @@ If you accidentally left the onboarding server, you can rejoin using {}
             .guild_id
             .parse::<GuildId>()?;
 
+        // Expire the old onboarding first, keeping it around for the history. Should tearing down
+        // the guild fail after this, the cleanup task tears down guilds of expired onboardings
+        if can_expire {
+            crate::history::expire_onboarding(
+                &ctx.data().pool,
+                state.id,
+                &ctx.author().id.to_string(),
+                "expired_restart",
+            )
+            .await?;
+        }
+
         let cache_http = botox::cache::CacheHttpImpl::from_ctx(ctx.serenity_context());
         delete_or_leave_guild(&cache_http, guild_id).await?;
 
-        // Delete onboarding
-        sqlx::query!(
-            "DELETE FROM staff_onboardings WHERE guild_id = $1",
-            state.guild_id
-        )
-        .execute(&ctx.data().pool)
-        .await?;
-
+        // The readme of the new guild is set up once the trainee joins it
         setup_guild(ctx, &mut msg).await?;
 
-        setup_readme(&cache_http, guild_id)
-        .await?;
-
         return Ok(false);
     }
 
@@ -185,12 +191,15 @@ If you accidentally left the onboarding server, you can rejoin using {}
         .into_message()
         .await?;
 
-        sqlx::query!(
-            "DELETE FROM staff_onboardings WHERE guild_id = $1",
-            state.guild_id
-        )
-        .execute(&ctx.data().pool)
-        .await?;
+        if can_expire {
+            crate::history::expire_onboarding(
+                &ctx.data().pool,
+                state.id,
+                &ctx.author().id.to_string(),
+                "guild_missing",
+            )
+            .await?;
+        }
 
         setup_guild(ctx, &mut msg).await?;
 
@@ -205,7 +214,7 @@ If you accidentally left the onboarding server, you can rejoin using {}
         // They're not in the right guild, so we need to ask them to move
         return Err(format!(
             "You are not in the correct guild! Go to {}",
-            ConfirmLoginState::JoinOnboardingServer(ctx.author().id).make_login_url(&ctx.cache().current_user().id.to_string()),
+            ConfirmLoginState::JoinOnboardingServer(ctx.author().id).create_login_url(),
         )
         .into());
     }
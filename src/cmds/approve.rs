@@ -55,7 +55,7 @@ pub async fn approve(
                 return Ok(());
             }
 
-            if reason.len() < 30 {
+            if reason.len() < crate::states::MIN_VERDICT_REASON_LEN {
                 ctx.send(
                     CreateReply::default().embed(
                         CreateEmbed::default()
@@ -81,9 +81,22 @@ pub async fn approve(
                 return Ok(());
             } */
 
+            let mut tx = data.pool.begin().await?;
+
+            crate::states::transition(
+                &mut *tx,
+                onboarding_id,
+                onboard_state,
+                crate::states::OnboardEvent::ApproveBot,
+                &crate::states::TransitionContext {
+                    reason: Some(&reason),
+                    ..crate::states::TransitionContext::new(&ctx.author().id.to_string())
+                },
+            )
+            .await?;
+
             sqlx::query!(
-                "UPDATE staff_onboardings SET state = $1, verdict = $2 WHERE user_id = $3 AND id = $4",
-                crate::states::OnboardState::InQuiz.to_string(),
+                "UPDATE staff_onboardings SET verdict = $1 WHERE user_id = $2 AND id = $3",
                 serde_json::json!({
                     "action": "approve",
                     "reason": reason,
@@ -92,9 +105,11 @@ pub async fn approve(
                 ctx.author().id.to_string(),
                 onboarding_id
             )
-            .execute(&data.pool)
+            .execute(&mut *tx)
             .await?;
 
+            tx.commit().await?;
+
             // Try kicking the test bot from the server now
             ctx.http()
                 .kick_member(
@@ -131,6 +146,6 @@ Visit {}/onboarding/quiz/{} to take the quiz!
             onboarding_id,
         )
         .into()),
-        _ => Err("Hmm... seems like you can't use this command yet!".into()), // TODO, remove
+        _ => Err("Hmm... seems like you can't use this command yet!".into()),
     }
 }
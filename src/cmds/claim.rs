@@ -155,13 +155,13 @@ pub async fn claim(
 
                 ctx.say("Great! With a real bot, things won't go this smoothly, but you can always remind people to test their bot! Now try claiming again, but this time use ``Force Claim``").await?; 
 
-                sqlx::query!(
-                    "UPDATE staff_onboardings SET state = $1 WHERE user_id = $2 AND id = $3",
-                    crate::states::OnboardState::QueueRemindedReviewer.to_string(),
-                    ctx.author().id.to_string(),
-                    onboarding_id
+                crate::states::transition(
+                    &mut *data.pool.acquire().await?,
+                    onboarding_id,
+                    onboard_state,
+                    crate::states::OnboardEvent::RemindReviewer,
+                    &crate::states::TransitionContext::new(&ctx.author().id.to_string()),
                 )
-                .execute(&data.pool)
                 .await?;
             }
 
@@ -229,13 +229,13 @@ pub async fn claim(
                     return Ok(());
                 }
 
-                sqlx::query!(
-                    "UPDATE staff_onboardings SET state = $1 WHERE user_id = $2 AND id = $3",
-                    crate::states::OnboardState::Claimed.to_string(),
-                    ctx.author().id.to_string(),
-                    onboarding_id
+                crate::states::transition(
+                    &mut *data.pool.acquire().await?,
+                    onboarding_id,
+                    onboard_state,
+                    crate::states::OnboardEvent::ForceClaim,
+                    &crate::states::TransitionContext::new(&ctx.author().id.to_string()),
                 )
-                .execute(&data.pool)
                 .await?;
 
                 let msg = CreateReply::default().embed(
@@ -268,6 +268,6 @@ pub async fn claim(
             Err(
                 "Hmm... seems like you can't use this command yet!".into()
             )
-        } // TODO, remove
+        }
     }
 }
@@ -107,13 +107,13 @@ Since you seem new to this place, how about a nice look arou-?
             )
             .await?;
 
-            sqlx::query!(
-                "UPDATE staff_onboardings SET state = $1 WHERE user_id = $2 AND id = $3",
-                crate::states::OnboardState::Started.to_string(),
-                ctx.author().id.to_string(),
-                onboarding_id
+            crate::states::transition(
+                &mut *data.pool.acquire().await?,
+                onboarding_id,
+                onboard_state,
+                crate::states::OnboardEvent::Queue,
+                &crate::states::TransitionContext::new(&ctx.author().id.to_string()),
             )
-            .execute(&data.pool)
             .await?;
 
             Ok(())
@@ -1,70 +1,370 @@
-use sqlx::{PgPool, types::uuid};
+use sqlx::types::{chrono, uuid};
+use sqlx::PgPool;
 
+use crate::config::CONFIG;
 use crate::Error;
 
+/// Number of incorrect codes that can be inputted before the user is locked out
+pub const MAX_CODE_ATTEMPTS: i32 = 5;
+
+/// How long a user is locked out for after too many incorrect codes
+pub const CODE_LOCKOUT_MINS: i32 = 15;
+
+/// Prefix of code seeds used by the HMAC based algorithm. Seeds without it were issued by the old algorithm
+const CODE_SEED_PREFIX: &str = "v2:";
+
+/// Length of a staff verification code
+const CODE_LEN: usize = 6;
+
+/// Creates a new random seed to derive the staff verification code of an onboarding from
+pub fn new_code_seed() -> String {
+    format!("{}{}", CODE_SEED_PREFIX, botox::crypto::gen_random(32))
+}
+
+/// Derives the staff verification code from a seed using HMAC keyed by ``staff_verify_secret``
+fn derive_code(onboarding_id: &str, user_id: &str, seed: &str) -> String {
+    let key = ring::hmac::Key::new(
+        ring::hmac::HMAC_SHA256,
+        CONFIG.staff_verify_secret.as_bytes(),
+    );
+    let tag = ring::hmac::sign(
+        &key,
+        format!("staff_verify:{}:{}:{}", onboarding_id, user_id, seed).as_bytes(),
+    );
+
+    data_encoding::HEXLOWER.encode(tag.as_ref())[..CODE_LEN].to_string()
+}
+
+/// Derives the staff verification code from a seed issued by the old algorithm
+///
+/// Returns ``None`` if the seed is too short to derive a code from
+fn derive_legacy_code(user_id: &str, seed: &str) -> Option<String> {
+    if seed.len() < 73 || !seed.is_ascii() || user_id.len() < 7 || !user_id.is_ascii() {
+        return None;
+    }
+
+    // Take last 73 characters
+    let mut code = seed[seed.len() - 73..].to_string();
+
+    code.replace_range(2..3, "r");
+    code.replace_range(19..20, &user_id[0..1]);
+    code.replace_range(21..22, &user_id[1..2]);
+    code.replace_range(40..41, &user_id[6..7]);
+    code.replace_range(39..40, "x");
+
+    let code = ring::digest::digest(&ring::digest::SHA512, code.as_bytes());
+    let code = data_encoding::HEXLOWER.encode(code.as_ref());
+
+    // Take last 6 characters
+    Some(code[code.len() - CODE_LEN..].to_string())
+}
+
+/// Returns what the staff guide should show for a code seed
+///
+/// This is the code itself for the HMAC based algorithm and the seed for the old algorithm
+pub fn guide_code(onboarding_id: &str, user_id: &str, seed: &str) -> String {
+    if seed.starts_with(CODE_SEED_PREFIX) {
+        derive_code(onboarding_id, user_id, seed)
+    } else {
+        seed.to_string()
+    }
+}
+
+/// Result of checking a staff verification code
+pub enum CodeCheck {
+    Correct,
+    Incorrect,
+    /// The inputted code can never be a staff verification code, this does not count as an attempt
+    Invalid,
+    /// The user is locked out, contains the minutes left
+    LockedOut(i64),
+}
+
+/// Checks an inputted staff verification code, counting incorrect attempts
+///
+/// After [`MAX_CODE_ATTEMPTS`] incorrect attempts, the user is locked out for [`CODE_LOCKOUT_MINS`]
+/// minutes and the code is rotated
 pub async fn check_code(
     pool: &PgPool,
     onboarding_id: &str,
     user_id: &str,
     inputted_code: &str,
-) -> Result<bool, Error> {
-    let inputted_code = inputted_code.replace(' ', "");
+) -> Result<CodeCheck, Error> {
+    let inputted_code = inputted_code.replace(' ', "").to_lowercase();
 
     // Make sure there are no unicode characters
     if inputted_code.chars().any(|c| !c.is_ascii_alphanumeric()) {
-        return Err("Unicode characters are not allowed".into());
+        return Ok(CodeCheck::Invalid);
     }
 
-    let code = sqlx::query!(
-        "SELECT staff_verify_code FROM staff_onboardings WHERE id = $1",
-        onboarding_id.parse::<uuid::Uuid>()?
+    let onboarding_id = onboarding_id.parse::<uuid::Uuid>()?;
+
+    let mut tx = pool.begin().await?;
+
+    let rec = sqlx::query!(
+        "SELECT staff_verify_code, staff_verify_attempts, staff_verify_locked_until FROM staff_onboardings WHERE id = $1 FOR UPDATE",
+        onboarding_id
     )
-    .fetch_one(pool)
+    .fetch_one(&mut *tx)
     .await?;
 
-    if let Some(code) = code.staff_verify_code {
-        // Take last 73 characters
-        let mut code = code.chars().skip(code.len() - 73).collect::<String>();
-
-        code.replace_range(2..3, "r");
-        code.replace_range(
-            19..20,
-            &user_id
-                .to_string()
-                .chars()
-                .next()
-                .unwrap_or_default()
-                .to_string(),
+    if let Some(locked_until) = rec.staff_verify_locked_until {
+        if locked_until > chrono::Utc::now() {
+            return Ok(CodeCheck::LockedOut(
+                (locked_until - chrono::Utc::now()).num_minutes() + 1,
+            ));
+        }
+    }
+
+    let Some(seed) = rec.staff_verify_code else {
+        return Ok(CodeCheck::Incorrect);
+    };
+
+    let id = onboarding_id.hyphenated().to_string();
+
+    let expected = if seed.starts_with(CODE_SEED_PREFIX) {
+        Some(derive_code(&id, user_id, &seed))
+    } else {
+        derive_legacy_code(user_id, &seed)
+    };
+
+    let correct = expected.is_some_and(|expected| {
+        ring::constant_time::verify_slices_are_equal(expected.as_bytes(), inputted_code.as_bytes())
+            .is_ok()
+    });
+
+    if correct {
+        sqlx::query!(
+            "UPDATE staff_onboardings SET staff_verify_attempts = 0 WHERE id = $1",
+            onboarding_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    } else if rec.staff_verify_attempts + 1 >= MAX_CODE_ATTEMPTS {
+        // Lock the user out and rotate the code so it can't be guessed across lockouts
+        sqlx::query!(
+            "UPDATE staff_onboardings SET staff_verify_attempts = 0, staff_verify_code = $1, staff_verify_locked_until = NOW() + make_interval(mins => $2) WHERE id = $3",
+            new_code_seed(),
+            CODE_LOCKOUT_MINS,
+            onboarding_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    } else {
+        sqlx::query!(
+            "UPDATE staff_onboardings SET staff_verify_attempts = staff_verify_attempts + 1 WHERE id = $1",
+            onboarding_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(if correct {
+        CodeCheck::Correct
+    } else {
+        CodeCheck::Incorrect
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONBOARDING_ID: &str = "7b0c5f0e-3d5e-4f7a-9c2e-1a2b3c4d5e6f";
+    const USER_ID: &str = "563808552288780322";
+    const LEGACY_SEED: &str =
+        "abcdefghijklmnopqrstuvwxyz0123456789abcdefghijklmnopqrstuvwxyz0123456789abcdefgh";
+
+    #[test]
+    fn codes_are_stable() {
+        // Codes are shown to trainees in the staff guide, so derivation must not change between
+        // releases while onboardings are in-flight
+        let seed = format!("{}{}", CODE_SEED_PREFIX, "a".repeat(32));
+
+        assert_eq!(derive_code(ONBOARDING_ID, USER_ID, &seed), "3aa76a");
+        assert_eq!(
+            derive_legacy_code(USER_ID, LEGACY_SEED).as_deref(),
+            Some("5b566c")
         );
-        code.replace_range(
-            21..22,
-            &user_id
-                .to_string()
-                .chars()
-                .nth(1)
-                .unwrap_or_default()
-                .to_string(),
+    }
+
+    #[test]
+    fn codes_are_bound_to_onboarding_user_and_seed() {
+        let seed = new_code_seed();
+        let code = derive_code(ONBOARDING_ID, USER_ID, &seed);
+
+        assert_eq!(code.len(), CODE_LEN);
+        assert_ne!(
+            code,
+            derive_code("00000000-0000-0000-0000-000000000000", USER_ID, &seed)
         );
-        code.replace_range(
-            40..41,
-            &user_id
-                .to_string()
-                .chars()
-                .nth(6)
-                .unwrap_or_default()
-                .to_string(),
+        assert_ne!(
+            code,
+            derive_code(ONBOARDING_ID, "728871946456137770", &seed)
         );
-        code.replace_range(39..40, "x");
+        assert_ne!(code, derive_code(ONBOARDING_ID, USER_ID, &new_code_seed()));
+    }
 
-        let code = code.as_bytes();
-        let code = ring::digest::digest(&ring::digest::SHA512, code);
-        let code = data_encoding::HEXLOWER.encode(code.as_ref());
+    #[test]
+    fn legacy_codes_only_use_the_last_73_characters() {
+        assert_eq!(
+            derive_legacy_code(USER_ID, LEGACY_SEED),
+            derive_legacy_code(USER_ID, &format!("ignored{}", LEGACY_SEED))
+        );
+    }
 
-        // Take last 6 characters
-        let code = code.chars().skip(code.len() - 6).collect::<String>();
+    #[test]
+    fn legacy_codes_reject_short_or_unicode_input() {
+        assert_eq!(derive_legacy_code(USER_ID, &LEGACY_SEED[..72]), None);
+        assert_eq!(derive_legacy_code("563808", LEGACY_SEED), None);
+        assert_eq!(
+            derive_legacy_code(USER_ID, &format!("é{}", &LEGACY_SEED[2..])),
+            None
+        );
+        assert_eq!(derive_legacy_code("56380855228878032é", LEGACY_SEED), None);
+    }
 
-        Ok(inputted_code == code)
-    } else {
-        Ok(false)
+    #[test]
+    fn guide_shows_code_or_legacy_seed() {
+        let seed = new_code_seed();
+
+        assert_eq!(
+            guide_code(ONBOARDING_ID, USER_ID, &seed),
+            derive_code(ONBOARDING_ID, USER_ID, &seed)
+        );
+        assert_eq!(guide_code(ONBOARDING_ID, USER_ID, LEGACY_SEED), LEGACY_SEED);
+    }
+
+    async fn onboarding(pool: &PgPool, seed: &str) -> String {
+        let id: uuid::Uuid = sqlx::query_scalar(
+            "INSERT INTO staff_onboardings (user_id, guild_id, staff_verify_code) VALUES ($1, '0', $2) RETURNING id",
+        )
+        .bind(USER_ID)
+        .bind(seed)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+
+        id.hyphenated().to_string()
+    }
+
+    async fn seed(pool: &PgPool, id: &str) -> String {
+        sqlx::query_scalar("SELECT staff_verify_code FROM staff_onboardings WHERE id = $1::uuid")
+            .bind(id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test(
+        migrations = false,
+        fixtures(path = "server/fixtures", scripts("base_schema"))
+    )]
+    async fn correct_codes_are_accepted(pool: PgPool) {
+        sqlx::migrate!().run(&pool).await.unwrap();
+
+        let id = onboarding(&pool, &new_code_seed()).await;
+        let code = derive_code(&id, USER_ID, &seed(&pool, &id).await);
+
+        assert!(matches!(
+            check_code(&pool, &id, USER_ID, &format!(" {} ", code.to_uppercase()))
+                .await
+                .unwrap(),
+            CodeCheck::Correct
+        ));
+
+        let legacy = onboarding(&pool, LEGACY_SEED).await;
+
+        assert!(matches!(
+            check_code(&pool, &legacy, USER_ID, "5b566c").await.unwrap(),
+            CodeCheck::Correct
+        ));
+    }
+
+    #[sqlx::test(
+        migrations = false,
+        fixtures(path = "server/fixtures", scripts("base_schema"))
+    )]
+    async fn malformed_codes_are_invalid_and_not_counted(pool: PgPool) {
+        sqlx::migrate!().run(&pool).await.unwrap();
+
+        let id = onboarding(&pool, &new_code_seed()).await;
+
+        for code in ["abc-12", "äbc123"] {
+            assert!(matches!(
+                check_code(&pool, &id, USER_ID, code).await.unwrap(),
+                CodeCheck::Invalid
+            ));
+        }
+
+        let attempts: i32 = sqlx::query_scalar(
+            "SELECT staff_verify_attempts FROM staff_onboardings WHERE id = $1::uuid",
+        )
+        .bind(&id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        assert_eq!(attempts, 0);
+    }
+
+    #[sqlx::test(
+        migrations = false,
+        fixtures(path = "server/fixtures", scripts("base_schema"))
+    )]
+    async fn incorrect_codes_lock_out_and_rotate_the_seed(pool: PgPool) {
+        sqlx::migrate!().run(&pool).await.unwrap();
+
+        let id = onboarding(&pool, &new_code_seed()).await;
+        let old_seed = seed(&pool, &id).await;
+        let old_code = derive_code(&id, USER_ID, &old_seed);
+
+        // A wrong code that can never collide with the real one
+        let wrong = "zzzzzz";
+
+        for _ in 0..MAX_CODE_ATTEMPTS {
+            assert!(matches!(
+                check_code(&pool, &id, USER_ID, wrong).await.unwrap(),
+                CodeCheck::Incorrect
+            ));
+        }
+
+        // Even the correct code is refused during the lockout
+        match check_code(&pool, &id, USER_ID, &old_code).await.unwrap() {
+            CodeCheck::LockedOut(mins) => {
+                assert!(mins > 0 && mins <= CODE_LOCKOUT_MINS as i64, "{}", mins)
+            }
+            _ => panic!("expected a lockout"),
+        }
+
+        let new_seed = seed(&pool, &id).await;
+        assert_ne!(new_seed, old_seed);
+        assert!(new_seed.starts_with(CODE_SEED_PREFIX));
+
+        // Let the lockout pass
+        sqlx::query(
+            "UPDATE staff_onboardings SET staff_verify_locked_until = NOW() - make_interval(mins => $1) WHERE id = $2::uuid",
+        )
+        .bind(CODE_LOCKOUT_MINS)
+        .bind(&id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let new_code = derive_code(&id, USER_ID, &new_seed);
+
+        if new_code != old_code {
+            assert!(matches!(
+                check_code(&pool, &id, USER_ID, &old_code).await.unwrap(),
+                CodeCheck::Incorrect
+            ));
+        }
+
+        assert!(matches!(
+            check_code(&pool, &id, USER_ID, &new_code).await.unwrap(),
+            CodeCheck::Correct
+        ));
     }
 }
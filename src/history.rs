@@ -0,0 +1,224 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::{types::uuid, PgConnection, PgPool};
+use ts_rs::TS;
+
+use crate::states::{OnboardEvent, OnboardState};
+use crate::Error;
+
+/// Actor used for changes made by Persepolis itself (such as the cleanup task)
+pub const SYSTEM_ACTOR: &str = "system";
+
+/// A single entry to be recorded in the onboarding history
+pub struct HistoryEntry<'a> {
+    pub onboarding_id: uuid::Uuid,
+    pub user_id: &'a str,
+    /// State before the event, ``None`` if the onboarding did not exist yet
+    pub from: Option<OnboardState>,
+    /// State after the event, ``None`` if the onboarding was deleted
+    pub to: Option<OnboardState>,
+    pub event: OnboardEvent,
+    /// User ID of whoever caused the event or [`SYSTEM_ACTOR`]
+    pub actor: &'a str,
+    pub metadata: serde_json::Value,
+}
+
+/// Records an entry in the onboarding history
+pub async fn record(conn: &mut PgConnection, entry: HistoryEntry<'_>) -> Result<(), Error> {
+    sqlx::query!(
+        "INSERT INTO staff_onboarding_transitions (onboarding_id, user_id, from_state, to_state, event, actor, metadata) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        entry.onboarding_id,
+        entry.user_id,
+        entry.from.map(|s| s.to_string()),
+        entry.to.map(|s| s.to_string()),
+        entry.event.to_string(),
+        entry.actor,
+        entry.metadata
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Number of onboardings in every state right now
+pub async fn count_by_state(pool: &PgPool) -> Result<Vec<(String, i64)>, Error> {
+    let rows = sqlx::query!(
+        "SELECT state, COUNT(*) AS count FROM staff_onboardings GROUP BY state ORDER BY state"
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| (r.state, r.count.unwrap_or_default()))
+        .collect())
+}
+
+/// Number of onboardings that moved to a state in the last 7 and 30 days
+pub async fn recent_transitions_to(
+    pool: &PgPool,
+    state: OnboardState,
+) -> Result<(i64, i64), Error> {
+    let rec = sqlx::query!(
+        "
+SELECT
+    COUNT(*) FILTER (WHERE created_at > NOW() - INTERVAL '7 days') AS week,
+    COUNT(*) AS month
+FROM staff_onboarding_transitions
+WHERE to_state = $1 AND created_at > NOW() - INTERVAL '30 days'
+        ",
+        state.to_string()
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok((rec.week.unwrap_or_default(), rec.month.unwrap_or_default()))
+}
+
+/// Expires an onboarding through the ``expire`` transition, recording the reason in the history
+///
+/// The row is kept rather than deleted so failed attempts can still be looked at.
+/// Does nothing if the onboarding does not exist, was voided or was already expired, such as by
+/// the cleanup task at the same time
+pub async fn expire_onboarding(
+    pool: &PgPool,
+    onboarding_id: uuid::Uuid,
+    actor: &str,
+    reason: &str,
+) -> Result<(), Error> {
+    let Some(state) = current_state(pool, onboarding_id).await? else {
+        return Ok(());
+    };
+
+    if state == OnboardState::Expired {
+        return Ok(());
+    }
+
+    let res = crate::states::transition(
+        &mut *pool.acquire().await?,
+        onboarding_id,
+        state,
+        OnboardEvent::Expire,
+        &crate::states::TransitionContext {
+            reason: Some(reason),
+            ..crate::states::TransitionContext::new(actor)
+        },
+    )
+    .await;
+
+    if let Err(e) = res {
+        // Fine if it lost the race to someone else expiring it
+        if current_state(pool, onboarding_id).await? != Some(OnboardState::Expired) {
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the state of an onboarding that was not voided
+async fn current_state(
+    pool: &PgPool,
+    onboarding_id: uuid::Uuid,
+) -> Result<Option<OnboardState>, Error> {
+    let rec = sqlx::query!(
+        "SELECT state FROM staff_onboardings WHERE id = $1 AND void = false",
+        onboarding_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(match rec {
+        Some(rec) => Some(rec.state.parse::<OnboardState>()?),
+        None => None,
+    })
+}
+
+/// An onboarding that expired, see [`expire_onboarding`]
+pub struct Expiration {
+    pub onboarding_id: uuid::Uuid,
+    pub user_id: String,
+    /// State the onboarding was in when it expired
+    pub from_state: Option<String>,
+    pub reason: Option<String>,
+    pub created_at: i64,
+}
+
+/// Returns the most recent expirations, newest first
+pub async fn recent_expirations(pool: &PgPool, limit: i64) -> Result<Vec<Expiration>, Error> {
+    let rows = sqlx::query!(
+        "
+SELECT onboarding_id, user_id, from_state, metadata->>'reason' AS reason, created_at
+FROM staff_onboarding_transitions
+WHERE to_state = $1
+ORDER BY created_at DESC
+LIMIT $2
+        ",
+        OnboardState::Expired.to_string(),
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| Expiration {
+            onboarding_id: r.onboarding_id,
+            user_id: r.user_id,
+            from_state: r.from_state,
+            reason: r.reason,
+            created_at: r.created_at.timestamp(),
+        })
+        .collect())
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/TimelineEntry.ts")]
+pub struct TimelineEntry {
+    pub onboarding_id: String,
+    pub from_state: Option<String>,
+    pub to_state: Option<String>,
+    pub event: String,
+    pub actor: String,
+    #[ts(type = "any")]
+    pub metadata: serde_json::Value,
+    pub created_at: i64,
+    /// Seconds spent in ``from_state`` before this entry, if known
+    pub time_in_from_state: Option<i64>,
+}
+
+/// Returns the history of all onboardings of a user (or just one of them), oldest first
+pub async fn get_timeline(
+    pool: &PgPool,
+    user_id: &str,
+    onboarding_id: Option<uuid::Uuid>,
+) -> Result<Vec<TimelineEntry>, Error> {
+    let rows = sqlx::query!(
+        "
+SELECT onboarding_id, from_state, to_state, event, actor, metadata, created_at,
+EXTRACT(EPOCH FROM created_at - LAG(created_at) OVER (PARTITION BY onboarding_id ORDER BY created_at))::BIGINT AS time_in_from_state
+FROM staff_onboarding_transitions
+WHERE user_id = $1 AND ($2::uuid IS NULL OR onboarding_id = $2)
+ORDER BY created_at ASC
+        ",
+        user_id,
+        onboarding_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| TimelineEntry {
+            onboarding_id: r.onboarding_id.hyphenated().to_string(),
+            from_state: r.from_state,
+            to_state: r.to_state,
+            event: r.event,
+            actor: r.actor,
+            metadata: r.metadata,
+            created_at: r.created_at.timestamp(),
+            time_in_from_state: r.time_in_from_state,
+        })
+        .collect())
+}
@@ -1,19 +1,26 @@
 use std::sync::Arc;
 
 use log::{error, info};
+use once_cell::sync::Lazy;
 use poise::serenity_prelude::{GuildId, FullEvent};
 use sqlx::{postgres::PgPoolOptions, PgPool};
 
 use botox::cache::CacheHttpImpl;
 
 mod admin;
+mod analytics;
 mod checks;
 mod cmds;
 mod config;
 mod finish;
 mod help;
+mod history;
+mod metrics;
+mod questions;
+mod quiz;
 mod server;
 mod setup;
+mod similarity;
 mod states;
 mod stats;
 mod perms;
@@ -37,39 +44,107 @@ async fn clean_out(pool: PgPool, cache_http: CacheHttpImpl) -> ! {
     }
 }
 
+async fn prune_sessions(pool: PgPool) -> ! {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(300));
+    loop {
+        interval.tick().await;
+
+        match server::auth::prune_expired_sessions(&pool).await {
+            Ok(0) => {}
+            Ok(count) => info!("Pruned {} expired sessions", count),
+            Err(e) => error!("Error while pruning sessions: {}", e),
+        }
+    }
+}
+
 async fn clean_out_impl(pool: &PgPool, cache_http: &CacheHttpImpl) -> Result<(), Error> {
+    let expirable = states::expirable_states()
+        .iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+
     let rows = sqlx::query!(
         "
-SELECT id, user_id, guild_id FROM staff_onboardings
--- The guild in question should never be pending manager review
-WHERE state != $1
--- Nor complete (!= $2)
-AND state != $2
+SELECT id, guild_id FROM staff_onboardings
+-- Only onboardings that have not been submitted yet can expire
+WHERE state = ANY($1)
+-- Voided onboardings can no longer change state
+AND void = false
 -- And has been created more than three hours ago
 AND created_at < NOW() - INTERVAL '3 hours'
         ",
-        states::OnboardState::PendingManagerReview.to_string(),
-        states::OnboardState::Completed.to_string()
+        &expirable
     )
     .fetch_all(pool)
     .await?;
 
+    // A failing onboarding must not hold up the others, it is retried on the next pass
     for row in rows {
-        sqlx::query!(
-            "DELETE FROM staff_onboardings WHERE id = $1",
-            row.id
-        )
-        .execute(pool)
-        .await?;
+        if let Err(e) = expire_stale(pool, cache_http, row.id, &row.guild_id).await {
+            error!("Error while expiring onboarding {}: {}", row.id, e);
+        }
+    }
+
+    // Guilds of onboardings that ended without being torn down, such as voided or denied ones
+    let guild_ids = cache_http
+        .cache
+        .guilds()
+        .iter()
+        .map(|g| g.to_string())
+        .collect::<Vec<_>>();
+
+    let stale_guilds = sqlx::query!(
+        "
+SELECT guild_id FROM staff_onboardings
+WHERE guild_id = ANY($1)
+AND (void = true OR state = $2 OR state = $3)
+AND created_at < NOW() - INTERVAL '3 hours'
+        ",
+        &guild_ids,
+        states::OnboardState::Denied.to_string(),
+        states::OnboardState::Expired.to_string()
+    )
+    .fetch_all(pool)
+    .await?;
 
-        let guild_id = row.guild_id.parse::<GuildId>()?;
+    for row in stale_guilds {
+        let res = match row.guild_id.parse::<GuildId>() {
+            Ok(guild_id) => setup::delete_or_leave_guild(cache_http, guild_id).await,
+            Err(e) => Err(e.into()),
+        };
 
-        setup::delete_or_leave_guild(cache_http, guild_id).await?;
+        if let Err(e) = res {
+            error!("Error while tearing down guild {}: {}", row.guild_id, e);
+        }
     }
 
     Ok(())
 }
 
+/// Tears down the guild of an onboarding and then expires it
+///
+/// The guild goes first so an onboarding is only expired once nothing is left of it
+async fn expire_stale(
+    pool: &PgPool,
+    cache_http: &CacheHttpImpl,
+    onboarding_id: sqlx::types::uuid::Uuid,
+    guild_id: &str,
+) -> Result<(), Error> {
+    setup::delete_or_leave_guild(cache_http, guild_id.parse::<GuildId>()?).await?;
+
+    history::expire_onboarding(
+        pool,
+        onboarding_id,
+        history::SYSTEM_ACTOR,
+        analytics::EXPIRED_REASON,
+    )
+    .await?;
+
+    metrics::METRICS.cleanup_expirations.inc();
+
+    Ok(())
+}
+
 #[poise::command(prefix_command)]
 async fn register(ctx: Context<'_>) -> Result<(), Error> {
     poise::builtins::register_application_commands_buttons(ctx).await?;
@@ -144,6 +219,13 @@ async fn event_listener<'a>(
             .execute(&user_data.pool)
             .await?;
 
+            questions::seed_from_config(&user_data.pool).await?;
+
+            // Quizzes are built from the question bank, which may have been edited since the blueprint was
+            questions::validate_bank(&mut *user_data.pool.acquire().await?).await?;
+
+            server::auth::hash_legacy_sessions(&user_data.pool).await?;
+
             let cache_http_server = CacheHttpImpl::from_ctx(ctx.serenity_context);
             tokio::task::spawn(server::api::setup_server(
                 user_data.pool.clone(),
@@ -155,6 +237,8 @@ async fn event_listener<'a>(
                 user_data.pool.clone(),
                 cache_http_cleanout,
             ));
+
+            tokio::task::spawn(prune_sessions(user_data.pool.clone()));
         }
         _ => {}
     }
@@ -170,6 +254,9 @@ async fn main() {
 
     env_logger::init();
 
+    // Start the uptime clock
+    Lazy::force(&metrics::METRICS);
+
     info!("Proxy URL: {}", config::CONFIG.proxy_url);
 
     let http = serenity::all::HttpBuilder::new(&config::CONFIG.token)
@@ -215,6 +302,8 @@ async fn main() {
             // This code is run before every command
             pre_command: |ctx| {
                 Box::pin(async move {
+                    metrics::METRICS.commands.inc(&ctx.command().qualified_name);
+
                     info!(
                         "Executing command {} for user {} ({})...",
                         ctx.command().qualified_name,
@@ -245,6 +334,8 @@ async fn main() {
         .await
         .expect("Error creating client");
 
+    let _ = metrics::SHARD_MANAGER.set(client.shard_manager.clone());
+
     if let Err(why) = client.start().await {
         error!("Client error: {:?}", why);
     }
@@ -0,0 +1,334 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::{Lazy, OnceCell};
+use serenity::all::{ConnectionStage, ShardManager};
+
+/// Global metrics, rendered in the Prometheus text format at ``/metrics``
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+/// Shard manager of the client, set before the client starts
+pub static SHARD_MANAGER: OnceCell<Arc<ShardManager>> = OnceCell::new();
+
+/// Upper bounds of the HTTP latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counters keyed by a single label
+#[derive(Default)]
+pub struct LabeledCounter(RwLock<BTreeMap<String, Counter>>);
+
+impl LabeledCounter {
+    pub fn inc(&self, label: &str) {
+        if let Some(counter) = self.0.read().unwrap_or_else(|e| e.into_inner()).get(label) {
+            counter.inc();
+            return;
+        }
+
+        self.0
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(label.to_string())
+            .or_default()
+            .inc();
+    }
+
+    pub fn values(&self) -> Vec<(String, u64)> {
+        self.0
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(label, counter)| (label.clone(), counter.get()))
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative. The last bucket is ``+Inf``
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|b| secs <= *b)
+            .unwrap_or(LATENCY_BUCKETS.len());
+
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Method, route and status of a request
+type RequestLabels = (String, String, u16);
+
+pub struct Metrics {
+    pub started_at: Instant,
+    /// Commands run, by qualified name
+    pub commands: LabeledCounter,
+    pub quiz_submissions: Counter,
+    /// Failed logins, by route
+    pub login_failures: LabeledCounter,
+    /// Onboardings expired by the cleanup task
+    pub cleanup_expirations: Counter,
+    http_latencies: RwLock<BTreeMap<RequestLabels, Histogram>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            started_at: Instant::now(),
+            commands: LabeledCounter::default(),
+            quiz_submissions: Counter::default(),
+            login_failures: LabeledCounter::default(),
+            cleanup_expirations: Counter::default(),
+            http_latencies: RwLock::default(),
+        }
+    }
+}
+
+impl Metrics {
+    /// Records a finished RPC request, ``route`` must be a route template to bound the labels
+    pub fn observe_request(&self, method: &str, route: &str, status: u16, duration: Duration) {
+        let labels = (method.to_string(), route.to_string(), status);
+
+        if let Some(histogram) = self
+            .http_latencies
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&labels)
+        {
+            histogram.observe(duration);
+            return;
+        }
+
+        self.http_latencies
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(labels)
+            .or_default()
+            .observe(duration);
+    }
+
+    /// Number of RPC requests per route, most requested first
+    pub fn request_counts(&self) -> Vec<(String, u64)> {
+        let mut counts = BTreeMap::<String, u64>::new();
+
+        for ((_, route, _), histogram) in self
+            .http_latencies
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+        {
+            *counts.entry(route.clone()).or_default() += histogram.count.load(Ordering::Relaxed);
+        }
+
+        let mut counts = counts.into_iter().collect::<Vec<_>>();
+        counts.sort_by(|a, b| b.1.cmp(&a.1));
+        counts
+    }
+
+    /// Renders the metrics in the Prometheus text format
+    ///
+    /// ``onboardings`` is the current number of onboardings per state, which is read from the database
+    pub fn render(&self, onboardings: &[(String, i64)]) -> String {
+        let mut out = String::new();
+
+        write_metric(
+            &mut out,
+            "persepolis_uptime_seconds",
+            "gauge",
+            "Seconds since persepolis started",
+        );
+        let _ = writeln!(
+            out,
+            "persepolis_uptime_seconds {}",
+            self.started_at.elapsed().as_secs()
+        );
+
+        write_metric(
+            &mut out,
+            "persepolis_commands_total",
+            "counter",
+            "Commands run",
+        );
+        for (command, count) in self.commands.values() {
+            let _ = writeln!(
+                out,
+                "persepolis_commands_total{{command=\"{}\"}} {}",
+                escape(&command),
+                count
+            );
+        }
+
+        write_metric(
+            &mut out,
+            "persepolis_onboardings",
+            "gauge",
+            "Onboardings by state",
+        );
+        for (state, count) in onboardings {
+            let _ = writeln!(
+                out,
+                "persepolis_onboardings{{state=\"{}\"}} {}",
+                escape(state),
+                count
+            );
+        }
+
+        write_metric(
+            &mut out,
+            "persepolis_quiz_submissions_total",
+            "counter",
+            "Quizzes submitted",
+        );
+        let _ = writeln!(
+            out,
+            "persepolis_quiz_submissions_total {}",
+            self.quiz_submissions.get()
+        );
+
+        write_metric(
+            &mut out,
+            "persepolis_login_failures_total",
+            "counter",
+            "Failed logins",
+        );
+        for (route, count) in self.login_failures.values() {
+            let _ = writeln!(
+                out,
+                "persepolis_login_failures_total{{route=\"{}\"}} {}",
+                escape(&route),
+                count
+            );
+        }
+
+        write_metric(
+            &mut out,
+            "persepolis_cleanup_expirations_total",
+            "counter",
+            "Onboardings expired by the cleanup task",
+        );
+        let _ = writeln!(
+            out,
+            "persepolis_cleanup_expirations_total {}",
+            self.cleanup_expirations.get()
+        );
+
+        write_metric(
+            &mut out,
+            "persepolis_http_request_duration_seconds",
+            "histogram",
+            "Latency of RPC requests",
+        );
+        for ((method, route, status), histogram) in self
+            .http_latencies
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+        {
+            let labels = format!(
+                "method=\"{}\",route=\"{}\",status=\"{}\"",
+                escape(method),
+                escape(route),
+                status
+            );
+
+            let mut cumulative = 0;
+
+            for (i, count) in histogram.buckets.iter().enumerate() {
+                cumulative += count.load(Ordering::Relaxed);
+
+                let le = LATENCY_BUCKETS
+                    .get(i)
+                    .map(|b| b.to_string())
+                    .unwrap_or_else(|| "+Inf".to_string());
+
+                let _ = writeln!(
+                    out,
+                    "persepolis_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, le, cumulative
+                );
+            }
+
+            let _ = writeln!(
+                out,
+                "persepolis_http_request_duration_seconds_sum{{{}}} {}",
+                labels,
+                histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+            );
+            let _ = writeln!(
+                out,
+                "persepolis_http_request_duration_seconds_count{{{}}} {}",
+                labels,
+                histogram.count.load(Ordering::Relaxed)
+            );
+        }
+
+        out
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Returns whether every shard is connected and the average gateway latency
+pub async fn gateway_status() -> (bool, Option<Duration>) {
+    let Some(shard_manager) = SHARD_MANAGER.get() else {
+        return (false, None);
+    };
+
+    let runners = shard_manager.runners.lock().await;
+
+    let connected = !runners.is_empty()
+        && runners
+            .values()
+            .all(|r| r.stage == ConnectionStage::Connected);
+
+    let latencies = runners
+        .values()
+        .filter_map(|r| r.latency)
+        .collect::<Vec<_>>();
+
+    let latency = (!latencies.is_empty())
+        .then(|| latencies.iter().sum::<Duration>() / latencies.len() as u32);
+
+    (connected, latency)
+}
@@ -0,0 +1,322 @@
+use std::fmt::{Display, Formatter};
+
+use log::{info, warn};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::{types::uuid, PgConnection, PgPool};
+use ts_rs::TS;
+
+use crate::config::{Question, CONFIG};
+use crate::Error;
+
+/// A question as stored in a quiz along with the question bank revision it was taken from
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/QuizQuestion.ts")]
+pub struct QuizQuestion {
+    /// ID of the question in the question bank, ``None`` for quizzes created before the question bank
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub revision: Option<i32>,
+    #[serde(flatten)]
+    pub question: Question,
+}
+
+impl AsRef<Question> for QuizQuestion {
+    fn as_ref(&self) -> &Question {
+        &self.question
+    }
+}
+
+/// A revision of a question in the question bank
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/BankQuestion.ts")]
+pub struct BankQuestion {
+    pub id: String,
+    pub revision: i32,
+    /// Whether the question can still be picked for new quizzes
+    pub active: bool,
+    pub question: Question,
+    pub created_by: String,
+    pub created_at: i64,
+}
+
+impl AsRef<Question> for BankQuestion {
+    fn as_ref(&self) -> &Question {
+        &self.question
+    }
+}
+
+impl BankQuestion {
+    /// Snapshots the question for storing in a quiz
+    pub fn to_quiz_question(&self) -> QuizQuestion {
+        QuizQuestion {
+            id: Some(self.id.clone()),
+            revision: Some(self.revision),
+            question: self.question.clone(),
+        }
+    }
+}
+
+/// Why a change to the question bank was rejected
+#[derive(Debug)]
+pub enum BankError {
+    /// The question does not exist or was removed
+    NotFound,
+    /// The active questions would no longer satisfy the quiz blueprint
+    BreaksBlueprint(String),
+}
+
+impl Display for BankError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BankError::NotFound => write!(f, "Question not found"),
+            BankError::BreaksBlueprint(e) => {
+                write!(f, "This change would break quiz creation: {}", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BankError {}
+
+/// Returns the latest revision of every question in the question bank
+pub async fn list(
+    conn: &mut PgConnection,
+    include_inactive: bool,
+) -> Result<Vec<BankQuestion>, Error> {
+    let rows = sqlx::query!(
+        "
+SELECT q.id, q.revision, q.active, r.data, r.created_by, r.created_at
+FROM staff_onboarding_questions q
+JOIN staff_onboarding_question_revisions r ON r.question_id = q.id AND r.revision = q.revision
+WHERE q.active OR $1
+ORDER BY q.created_at ASC
+        ",
+        include_inactive
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let mut questions = vec![];
+
+    for row in rows {
+        questions.push(BankQuestion {
+            id: row.id.hyphenated().to_string(),
+            revision: row.revision,
+            active: row.active,
+            question: serde_json::from_value(row.data)?,
+            created_by: row.created_by,
+            created_at: row.created_at.timestamp(),
+        });
+    }
+
+    Ok(questions)
+}
+
+/// Returns every revision of a question, oldest first
+pub async fn revisions(pool: &PgPool, id: uuid::Uuid) -> Result<Vec<BankQuestion>, Error> {
+    let rows = sqlx::query!(
+        "
+SELECT r.revision, r.data, r.created_by, r.created_at, q.active
+FROM staff_onboarding_question_revisions r
+JOIN staff_onboarding_questions q ON q.id = r.question_id
+WHERE r.question_id = $1
+ORDER BY r.revision ASC
+        ",
+        id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut questions = vec![];
+
+    for row in rows {
+        questions.push(BankQuestion {
+            id: id.hyphenated().to_string(),
+            revision: row.revision,
+            active: row.active,
+            question: serde_json::from_value(row.data)?,
+            created_by: row.created_by,
+            created_at: row.created_at.timestamp(),
+        });
+    }
+
+    Ok(questions)
+}
+
+/// Returns a specific revision of a question, if it exists
+pub async fn get_revision(
+    pool: &PgPool,
+    id: uuid::Uuid,
+    revision: i32,
+) -> Result<Option<Question>, Error> {
+    let rec = sqlx::query!(
+        "SELECT data FROM staff_onboarding_question_revisions WHERE question_id = $1 AND revision = $2",
+        id,
+        revision
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    match rec {
+        Some(rec) => Ok(Some(serde_json::from_value(rec.data)?)),
+        None => Ok(None),
+    }
+}
+
+/// Adds a question to the question bank
+pub async fn create(pool: &PgPool, question: &Question, actor: &str) -> Result<String, Error> {
+    let mut tx = pool.begin().await?;
+
+    lock_bank(&mut *tx).await?;
+
+    let rec = sqlx::query!("INSERT INTO staff_onboarding_questions DEFAULT VALUES RETURNING id")
+        .fetch_one(&mut *tx)
+        .await?;
+
+    sqlx::query!(
+        "INSERT INTO staff_onboarding_question_revisions (question_id, revision, data, created_by) VALUES ($1, 1, $2, $3)",
+        rec.id,
+        serde_json::to_value(question)?,
+        actor
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    validate_bank(&mut *tx).await?;
+
+    tx.commit().await?;
+
+    Ok(rec.id.hyphenated().to_string())
+}
+
+/// Edits a question by creating a new revision of it, returning the new revision
+pub async fn update(
+    pool: &PgPool,
+    id: uuid::Uuid,
+    question: &Question,
+    actor: &str,
+) -> Result<i32, Error> {
+    let mut tx = pool.begin().await?;
+
+    lock_bank(&mut *tx).await?;
+
+    let rec = sqlx::query!(
+        "UPDATE staff_onboarding_questions SET revision = revision + 1 WHERE id = $1 AND active = true RETURNING revision",
+        id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(BankError::NotFound)?;
+
+    sqlx::query!(
+        "INSERT INTO staff_onboarding_question_revisions (question_id, revision, data, created_by) VALUES ($1, $2, $3, $4)",
+        id,
+        rec.revision,
+        serde_json::to_value(question)?,
+        actor
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    validate_bank(&mut *tx).await?;
+
+    tx.commit().await?;
+
+    Ok(rec.revision)
+}
+
+/// Removes a question from the question bank
+///
+/// The question is only deactivated so onboardings which asked it can still resolve it
+pub async fn deactivate(pool: &PgPool, id: uuid::Uuid) -> Result<(), Error> {
+    let mut tx = pool.begin().await?;
+
+    lock_bank(&mut *tx).await?;
+
+    let res = sqlx::query!(
+        "UPDATE staff_onboarding_questions SET active = false WHERE id = $1 AND active = true",
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(BankError::NotFound.into());
+    }
+
+    validate_bank(&mut *tx).await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Locks the question bank against other changes until the transaction ends
+///
+/// Every change is validated against the whole bank, so two changes that each pass on their own
+/// (such as deactivating two different questions) must not be able to commit together
+async fn lock_bank(conn: &mut PgConnection) -> Result<(), Error> {
+    // Conflicts with itself but not with reads, so quizzes can still be built meanwhile
+    sqlx::query("LOCK TABLE staff_onboarding_questions IN SHARE ROW EXCLUSIVE MODE")
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Makes sure the active questions can still satisfy the quiz blueprint
+pub async fn validate_bank(conn: &mut PgConnection) -> Result<(), Error> {
+    let questions = list(conn, false).await?;
+
+    crate::quiz::validate(&CONFIG.quiz, &questions)
+        .map_err(|e| BankError::BreaksBlueprint(e.to_string()).into())
+}
+
+/// Imports the questions in config.yaml into the question bank if it is empty
+pub async fn seed_from_config(pool: &PgPool) -> Result<(), Error> {
+    let count = sqlx::query!("SELECT COUNT(*) FROM staff_onboarding_questions")
+        .fetch_one(pool)
+        .await?
+        .count
+        .unwrap_or(0);
+
+    if count > 0 {
+        if !CONFIG.questions.is_empty() {
+            warn!(
+                "Question bank is already seeded, ignoring the {} questions in config.yaml. Manage questions through the API instead",
+                CONFIG.questions.len()
+            );
+        }
+
+        return Ok(());
+    }
+
+    info!(
+        "Question bank is empty, importing {} questions from config.yaml",
+        CONFIG.questions.len()
+    );
+
+    let mut tx = pool.begin().await?;
+
+    for question in &CONFIG.questions {
+        let rec =
+            sqlx::query!("INSERT INTO staff_onboarding_questions DEFAULT VALUES RETURNING id")
+                .fetch_one(&mut *tx)
+                .await?;
+
+        sqlx::query!(
+            "INSERT INTO staff_onboarding_question_revisions (question_id, revision, data, created_by) VALUES ($1, 1, $2, $3)",
+            rec.id,
+            serde_json::to_value(question)?,
+            crate::history::SYSTEM_ACTOR
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
@@ -0,0 +1,940 @@
+use std::collections::HashMap;
+
+use indexmap::IndexMap;
+use rand::seq::SliceRandom;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::config::{Question, QuestionData, QuizBlueprint};
+use crate::Error;
+
+/// The kinds of questions a blueprint asks for, in the order they appear in a quiz
+const KINDS: [QuestionKind; 3] = [
+    QuestionKind::MultipleChoice,
+    QuestionKind::Short,
+    QuestionKind::Long,
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum QuestionKind {
+    MultipleChoice,
+    Short,
+    Long,
+}
+
+impl QuestionKind {
+    pub fn of(data: &QuestionData) -> Self {
+        match data {
+            QuestionData::MultipleChoice(_) => QuestionKind::MultipleChoice,
+            QuestionData::Short => QuestionKind::Short,
+            QuestionData::Long => QuestionKind::Long,
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            QuestionKind::MultipleChoice => 0,
+            QuestionKind::Short => 1,
+            QuestionKind::Long => 2,
+        }
+    }
+}
+
+impl QuizBlueprint {
+    /// Returns how many (non-pinned) questions of a kind the blueprint asks for
+    pub fn count(&self, kind: QuestionKind) -> usize {
+        match kind {
+            QuestionKind::MultipleChoice => self.multiple_choice,
+            QuestionKind::Short => self.short,
+            QuestionKind::Long => self.long,
+        }
+    }
+
+    /// Checks the blueprint on its own, mistakes that no question pool could make up for
+    pub fn check(&self) -> Result<(), Error> {
+        if self.time_limit == Some(0) {
+            return Err(
+                "Quiz time limit must be at least one minute, leave it unset for no limit".into(),
+            );
+        }
+
+        if self.tags.iter().any(|t| t.trim().is_empty()) {
+            return Err("Quiz blueprint tags must not be empty".into());
+        }
+
+        if self.min_per_section.keys().any(|s| s.trim().is_empty()) {
+            return Err("Quiz blueprint section names must not be empty".into());
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether a question may be randomly picked under this blueprint
+    fn is_eligible(&self, q: &Question) -> bool {
+        !q.pinned && (self.tags.is_empty() || q.tags.iter().any(|t| self.tags.contains(t)))
+    }
+}
+
+/// Checks that the question pool can always satisfy the blueprint
+pub fn validate<T: AsRef<Question>>(
+    blueprint: &QuizBlueprint,
+    questions: &[T],
+) -> Result<(), Error> {
+    blueprint.check()?;
+
+    for q in questions.iter().map(AsRef::<Question>::as_ref) {
+        if !q.weight.is_finite() || q.weight <= 0.0 {
+            return Err(format!("Question '{}' must have a positive weight", q.question).into());
+        }
+
+        if let Some(ref correct) = q.correct_answers {
+            let QuestionData::MultipleChoice(ref choices) = q.data else {
+                return Err(format!(
+                    "Question '{}' has correct answers but is not multiple choice",
+                    q.question
+                )
+                .into());
+            };
+
+            if correct.is_empty() || correct.iter().any(|c| !choices.contains(c)) {
+                return Err(format!(
+                    "Correct answers of question '{}' must be a non-empty subset of its choices",
+                    q.question
+                )
+                .into());
+            }
+        }
+    }
+
+    check_references(blueprint, questions)?;
+
+    check_available(blueprint, questions)?;
+
+    plan_sections(blueprint, questions, KINDS)?;
+
+    Ok(())
+}
+
+/// Checks that the tags and sections the blueprint names exist and that the section minimums fit
+/// in the quiz, these are most likely typos
+fn check_references<T: AsRef<Question>>(
+    blueprint: &QuizBlueprint,
+    questions: &[T],
+) -> Result<(), Error> {
+    let questions = questions
+        .iter()
+        .map(AsRef::<Question>::as_ref)
+        .collect::<Vec<&Question>>();
+
+    for tag in &blueprint.tags {
+        if !questions.iter().any(|q| q.tags.contains(tag)) {
+            return Err(format!("Quiz blueprint tag '{}' is not used by any question", tag).into());
+        }
+    }
+
+    let mut needed = 0;
+
+    for (section, min) in &blueprint.min_per_section {
+        if !questions
+            .iter()
+            .any(|q| q.section.as_ref() == Some(section))
+        {
+            return Err(format!("Quiz blueprint section '{}' has no questions", section).into());
+        }
+
+        // Pinned questions are always asked, so they count towards their section for free
+        let pinned = questions
+            .iter()
+            .filter(|q| q.pinned && q.section.as_ref() == Some(section))
+            .count();
+
+        needed += min.saturating_sub(pinned);
+    }
+
+    let picked = KINDS
+        .iter()
+        .map(|kind| blueprint.count(*kind))
+        .sum::<usize>();
+
+    if needed > picked {
+        return Err(format!(
+            "Quiz blueprint section minimums need {} picked questions but the blueprint only picks {}",
+            needed, picked
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Checks that there are enough eligible questions of every kind the blueprint asks for
+fn check_available<T: AsRef<Question>>(
+    blueprint: &QuizBlueprint,
+    questions: &[T],
+) -> Result<(), Error> {
+    for kind in KINDS {
+        let available = questions
+            .iter()
+            .map(AsRef::<Question>::as_ref)
+            .filter(|q| blueprint.is_eligible(q) && QuestionKind::of(&q.data) == kind)
+            .count();
+
+        if available < blueprint.count(kind) {
+            return Err(format!(
+                "Quiz blueprint asks for {} {:?} questions but only {} are available",
+                blueprint.count(kind),
+                kind,
+                available
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Randomly builds a quiz from the question pool according to the blueprint
+///
+/// Questions are ordered by kind (multiple choice, short, long) followed by the pinned questions
+pub fn build_quiz<T: AsRef<Question> + Clone>(
+    blueprint: &QuizBlueprint,
+    questions: &[T],
+) -> Result<Vec<T>, Error> {
+    // Picking fewer questions than asked for would silently give a short quiz
+    check_available(blueprint, questions)?;
+
+    let mut rng = rand::thread_rng();
+
+    // Shuffling the order kinds are tried in keeps section constraints from always
+    // being satisfied by the same kind of question
+    let mut order = KINDS;
+    order.shuffle(&mut rng);
+
+    let quotas = plan_sections(blueprint, questions, order)?;
+
+    let question = move |i: usize| questions[i].as_ref();
+
+    // Questions are referred to by their index in ``questions`` from here on
+    let eligible = (0..questions.len())
+        .filter(|i| blueprint.is_eligible(question(*i)))
+        .collect::<Vec<usize>>();
+
+    let mut chosen: Vec<usize> = vec![];
+    let mut remaining = KINDS.map(|kind| blueprint.count(kind));
+
+    for (section, quota) in quotas.iter() {
+        for kind in KINDS {
+            let n = quota[kind.index()];
+
+            if n == 0 {
+                continue;
+            }
+
+            let candidates = eligible
+                .iter()
+                .filter(|i| {
+                    let q = question(**i);
+                    q.section.as_ref() == Some(section) && QuestionKind::of(&q.data) == kind
+                })
+                .cloned()
+                .collect::<Vec<usize>>();
+
+            chosen
+                .extend(candidates.choose_multiple_weighted(&mut rng, n, |i| question(*i).weight)?);
+            remaining[kind.index()] -= n;
+        }
+    }
+
+    for kind in KINDS {
+        let candidates = eligible
+            .iter()
+            .filter(|i| QuestionKind::of(&question(**i).data) == kind && !chosen.contains(i))
+            .cloned()
+            .collect::<Vec<usize>>();
+
+        chosen.extend(candidates.choose_multiple_weighted(
+            &mut rng,
+            remaining[kind.index()],
+            |i| question(*i).weight,
+        )?);
+    }
+
+    chosen.sort_by_key(|i| QuestionKind::of(&question(*i).data).index());
+
+    let mut final_questions = chosen
+        .into_iter()
+        .map(|i| questions[i].clone())
+        .collect::<Vec<T>>();
+
+    // Add pinned questions
+    for q in questions {
+        if q.as_ref().pinned {
+            final_questions.push(q.clone());
+        }
+    }
+
+    Ok(final_questions)
+}
+
+/// Minimum length of an answer to a short answer question
+pub const MIN_SHORT_ANSWER_LEN: usize = 50;
+
+/// Minimum length of an answer to a long answer question
+pub const MIN_LONG_ANSWER_LEN: usize = 750;
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/AnswerFeedback.ts")]
+pub struct AnswerFeedback {
+    pub question: String,
+    /// Why the answer would be rejected on submission, ``None`` if it is valid
+    pub error: Option<String>,
+}
+
+/// Checks the answer to every question, returning feedback for each of them in order
+pub fn check_answers<T: AsRef<Question>>(
+    questions: &[T],
+    answers: &HashMap<String, String>,
+) -> Vec<AnswerFeedback> {
+    questions
+        .iter()
+        .map(AsRef::<Question>::as_ref)
+        .map(|q| {
+            let error = match (answers.get(&q.question), &q.data) {
+                (None, _) => Some("Missing answer".to_string()),
+                (Some(answer), QuestionData::Short) if answer.len() < MIN_SHORT_ANSWER_LEN => {
+                    Some(format!(
+                        "Short answer questions must be at least {} characters long",
+                        MIN_SHORT_ANSWER_LEN
+                    ))
+                }
+                (Some(answer), QuestionData::Long) if answer.len() < MIN_LONG_ANSWER_LEN => {
+                    Some(format!(
+                        "Long answer questions must be at least {} characters long",
+                        MIN_LONG_ANSWER_LEN
+                    ))
+                }
+                (Some(answer), QuestionData::MultipleChoice(choices))
+                    if !choices.contains(answer) =>
+                {
+                    Some("Invalid answer for multiple choice question".to_string())
+                }
+                _ => None,
+            };
+
+            AnswerFeedback {
+                question: q.question.clone(),
+                error,
+            }
+        })
+        .collect()
+}
+
+/// Answers saved while the quiz is being taken
+#[derive(Serialize, Deserialize, Clone)]
+pub struct QuizDraft {
+    /// ``cache_nonce`` of the quiz the draft belongs to. Drafts of an older quiz are discarded
+    pub cache_nonce: String,
+    pub answers: HashMap<String, String>,
+    #[serde(default)]
+    pub timings: HashMap<String, QuestionTiming>,
+    pub updated_at: i64,
+}
+
+/// Seconds a submission may arrive after the time limit to account for network latency
+pub const QUIZ_SUBMIT_GRACE_SECS: i64 = 30;
+
+/// When a question was first shown and last edited, as reported by the panel
+#[derive(Serialize, Deserialize, Clone, Copy, Default, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/QuestionTiming.ts")]
+pub struct QuestionTiming {
+    pub first_seen: Option<i64>,
+    pub last_edited: Option<i64>,
+}
+
+impl QuestionTiming {
+    /// Merges newly reported timestamps into the saved ones, clamping them to ``[start, now]``
+    pub fn merge(self, new: QuestionTiming, start: i64, now: i64) -> QuestionTiming {
+        let clamp = |t: Option<i64>| t.map(|t| t.clamp(start, now));
+
+        QuestionTiming {
+            first_seen: match (self.first_seen, clamp(new.first_seen)) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            },
+            last_edited: match (self.last_edited, clamp(new.last_edited)) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                (a, b) => a.or(b),
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/QuizTimings.ts")]
+pub struct QuizTimings {
+    /// When the quiz was created, recorded by the server
+    pub started_at: i64,
+    pub submitted_at: i64,
+    /// Time limit in seconds that applied to the quiz, if any
+    pub time_limit: Option<i64>,
+    pub questions: HashMap<String, QuestionTiming>,
+}
+
+/// Returns the time by which a quiz started at ``started_at`` must be submitted, if it has a time limit
+pub fn quiz_deadline(bp: &QuizBlueprint, started_at: i64) -> Option<i64> {
+    bp.time_limit.map(|mins| started_at + (mins as i64) * 60)
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/QuestionScore.ts")]
+pub struct QuestionScore {
+    pub question: String,
+    pub answer: String,
+    pub correct: bool,
+    pub points: u32,
+    pub max_points: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/ScoreBreakdown.ts")]
+pub struct ScoreBreakdown {
+    /// Scores of every automatically graded question
+    pub questions: Vec<QuestionScore>,
+    pub points: u32,
+    pub max_points: u32,
+}
+
+/// Grades all multiple choice questions that have an answer key
+///
+/// Questions without an answer key (and missing answers) are skipped and must be graded by hand
+pub fn grade_mcq<T: AsRef<Question>>(
+    questions: &[T],
+    answers: &HashMap<String, String>,
+) -> ScoreBreakdown {
+    let mut breakdown = ScoreBreakdown::default();
+
+    for q in questions.iter().map(AsRef::<Question>::as_ref) {
+        let (Some(correct), Some(answer)) = (&q.correct_answers, answers.get(&q.question)) else {
+            continue;
+        };
+
+        let max_points = q.points.unwrap_or(1);
+        let is_correct = correct.contains(answer);
+        let points = if is_correct { max_points } else { 0 };
+
+        breakdown.points += points;
+        breakdown.max_points += max_points;
+        breakdown.questions.push(QuestionScore {
+            question: q.question.clone(),
+            answer: answer.clone(),
+            correct: is_correct,
+            points,
+            max_points,
+        });
+    }
+
+    breakdown
+}
+
+/// Returns whether a question has to be graded by hand by a manager
+pub fn needs_manual_grading(q: &Question) -> bool {
+    q.correct_answers.is_none()
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/ManualGrade.ts")]
+pub struct ManualGrade {
+    pub question: String,
+    pub points: u32,
+    pub max_points: u32,
+    /// Comment of the grader against the guidance of the question
+    pub comment: Option<String>,
+    pub graded_by: String,
+    pub graded_at: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/GradeBreakdown.ts")]
+pub struct GradeBreakdown {
+    /// Grades of every manually graded question graded so far
+    pub questions: Vec<ManualGrade>,
+    /// Total points, including automatically graded ones
+    pub points: u32,
+    /// Maximum total points, including automatically graded ones
+    pub max_points: u32,
+    /// Whether every question that needs manual grading has been graded
+    pub complete: bool,
+}
+
+impl GradeBreakdown {
+    /// Computes the totals of the manual grades combined with the automatic score
+    pub fn new(
+        questions: &[Question],
+        score: &ScoreBreakdown,
+        mut grades: Vec<ManualGrade>,
+    ) -> Self {
+        // Keep grades in the order the questions were asked
+        grades.sort_by_key(|g| questions.iter().position(|q| q.question == g.question));
+
+        let mut manual = questions.iter().filter(|q| needs_manual_grading(q));
+
+        Self {
+            points: score.points + grades.iter().map(|g| g.points).sum::<u32>(),
+            max_points: score.max_points
+                + manual.clone().map(|q| q.points.unwrap_or(1)).sum::<u32>(),
+            complete: manual.all(|q| grades.iter().any(|g| g.question == q.question)),
+            questions: grades,
+        }
+    }
+}
+
+/// Returns whether grading of a submitted onboarding is complete given its stored
+/// ``questions`` and ``grades``
+pub fn is_grading_complete(
+    questions: Option<serde_json::Value>,
+    grades: Option<serde_json::Value>,
+) -> Result<bool, Error> {
+    if let Some(grades) = grades {
+        return Ok(serde_json::from_value::<GradeBreakdown>(grades)?.complete);
+    }
+
+    // Nothing graded yet, so grading is only complete if there is nothing to grade
+    let questions = match questions {
+        Some(questions) => serde_json::from_value::<Vec<Question>>(questions)?,
+        None => vec![],
+    };
+
+    Ok(!questions.iter().any(needs_manual_grading))
+}
+
+/// Works out how many questions of each kind must be picked from each section in
+/// ``min_per_section`` so that every section minimum is met without going over the
+/// blueprint's count for any kind
+///
+/// This is a max-flow problem (sections -> kinds) which is small enough to solve with plain
+/// augmenting paths. ``order`` decides which kinds are tried first
+fn plan_sections<T: AsRef<Question>>(
+    blueprint: &QuizBlueprint,
+    questions: &[T],
+    order: [QuestionKind; 3],
+) -> Result<IndexMap<String, [usize; 3]>, Error> {
+    let questions = questions
+        .iter()
+        .map(AsRef::<Question>::as_ref)
+        .collect::<Vec<&Question>>();
+
+    let sections = blueprint
+        .min_per_section
+        .iter()
+        .map(|(section, min)| {
+            let pinned = questions
+                .iter()
+                .filter(|q| q.pinned && q.section.as_ref() == Some(section))
+                .count();
+
+            (section, min.saturating_sub(pinned))
+        })
+        .collect::<Vec<_>>();
+
+    // Nodes are the source, one per section, one per kind and the sink
+    let source = 0;
+    let kind_node = |kind: QuestionKind| {
+        1 + sections.len() + order.iter().position(|k| *k == kind).unwrap_or_default()
+    };
+    let sink = sections.len() + KINDS.len() + 1;
+
+    let mut cap = vec![vec![0usize; sink + 1]; sink + 1];
+    let mut needed = 0;
+
+    for (i, (section, need)) in sections.iter().enumerate() {
+        cap[source][i + 1] = *need;
+        needed += need;
+
+        for kind in KINDS {
+            cap[i + 1][kind_node(kind)] = questions
+                .iter()
+                .filter(|q| {
+                    blueprint.is_eligible(q)
+                        && q.section.as_ref() == Some(*section)
+                        && QuestionKind::of(&q.data) == kind
+                })
+                .count();
+        }
+    }
+
+    for kind in KINDS {
+        cap[kind_node(kind)][sink] = blueprint.count(kind);
+    }
+
+    let original = cap.clone();
+
+    if max_flow(&mut cap, source, sink) < needed {
+        return Err(
+            "The question pool cannot satisfy the section minimums of the quiz blueprint".into(),
+        );
+    }
+
+    let mut quotas = IndexMap::new();
+
+    for (i, (section, _)) in sections.iter().enumerate() {
+        let mut quota = [0; 3];
+
+        for kind in KINDS {
+            let node = kind_node(kind);
+            quota[kind.index()] = original[i + 1][node] - cap[i + 1][node];
+        }
+
+        quotas.insert(section.to_string(), quota);
+    }
+
+    Ok(quotas)
+}
+
+fn max_flow(cap: &mut [Vec<usize>], source: usize, sink: usize) -> usize {
+    let mut total = 0;
+
+    loop {
+        let mut visited = vec![false; cap.len()];
+        let pushed = augment(cap, source, sink, usize::MAX, &mut visited);
+
+        if pushed == 0 {
+            return total;
+        }
+
+        total += pushed;
+    }
+}
+
+fn augment(
+    cap: &mut [Vec<usize>],
+    node: usize,
+    sink: usize,
+    limit: usize,
+    visited: &mut [bool],
+) -> usize {
+    if node == sink {
+        return limit;
+    }
+
+    visited[node] = true;
+
+    for next in 0..cap.len() {
+        if visited[next] || cap[node][next] == 0 {
+            continue;
+        }
+
+        let pushed = augment(cap, next, sink, limit.min(cap[node][next]), visited);
+
+        if pushed > 0 {
+            cap[node][next] -= pushed;
+            cap[next][node] += pushed;
+            return pushed;
+        }
+    }
+
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn question(name: &str, data: QuestionData, section: Option<&str>) -> Question {
+        Question {
+            question: name.to_string(),
+            data,
+            guidance: None,
+            pinned: false,
+            section: section.map(|s| s.to_string()),
+            tags: vec![],
+            weight: 1.0,
+            correct_answers: None,
+            points: None,
+        }
+    }
+
+    fn mcq(name: &str, section: Option<&str>) -> Question {
+        question(
+            name,
+            QuestionData::MultipleChoice(vec!["a".to_string(), "b".to_string()]),
+            section,
+        )
+    }
+
+    fn short(name: &str, section: Option<&str>) -> Question {
+        question(name, QuestionData::Short, section)
+    }
+
+    fn blueprint(
+        multiple_choice: usize,
+        short: usize,
+        long: usize,
+        min_per_section: &[(&str, usize)],
+    ) -> QuizBlueprint {
+        QuizBlueprint {
+            multiple_choice,
+            short,
+            long,
+            min_per_section: min_per_section
+                .iter()
+                .map(|(s, n)| (s.to_string(), *n))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn count_kind(quiz: &[Question], kind: QuestionKind) -> usize {
+        quiz.iter()
+            .filter(|q| QuestionKind::of(&q.data) == kind)
+            .count()
+    }
+
+    fn count_section(quiz: &[Question], section: &str) -> usize {
+        quiz.iter()
+            .filter(|q| q.section.as_deref() == Some(section))
+            .count()
+    }
+
+    #[test]
+    fn section_quotas_are_met_exactly() {
+        let mut pool = vec![
+            mcq("a1", Some("a")),
+            mcq("a2", Some("a")),
+            mcq("a3", Some("a")),
+            short("a4", Some("a")),
+            short("a5", Some("a")),
+            mcq("b1", Some("b")),
+            mcq("b2", Some("b")),
+            mcq("b3", Some("b")),
+        ];
+
+        for i in 0..5 {
+            pool.push(mcq(&format!("m{}", i), None));
+            pool.push(short(&format!("s{}", i), None));
+        }
+
+        let bp = blueprint(4, 2, 0, &[("a", 2), ("b", 1)]);
+
+        validate(&bp, &pool).unwrap();
+
+        let quotas = plan_sections(&bp, &pool, KINDS).unwrap();
+        assert_eq!(quotas["a"].iter().sum::<usize>(), 2);
+        assert_eq!(quotas["b"].iter().sum::<usize>(), 1);
+
+        for _ in 0..100 {
+            let quiz = build_quiz(&bp, &pool).unwrap();
+
+            assert_eq!(quiz.len(), 6);
+            assert_eq!(count_kind(&quiz, QuestionKind::MultipleChoice), 4);
+            assert_eq!(count_kind(&quiz, QuestionKind::Short), 2);
+            assert_eq!(count_kind(&quiz, QuestionKind::Long), 0);
+            assert!(count_section(&quiz, "a") >= 2);
+            assert!(count_section(&quiz, "b") >= 1);
+        }
+    }
+
+    #[test]
+    fn pinned_questions_count_towards_their_section() {
+        let mut pinned = short("pinned", Some("a"));
+        pinned.pinned = true;
+
+        let pool = vec![pinned, mcq("a1", Some("a")), mcq("m1", None)];
+        let bp = blueprint(1, 0, 0, &[("a", 1)]);
+
+        let quotas = plan_sections(&bp, &pool, KINDS).unwrap();
+        assert_eq!(quotas["a"], [0, 0, 0]);
+
+        let quiz = build_quiz(&bp, &pool).unwrap();
+        assert_eq!(quiz.len(), 2);
+        assert_eq!(quiz.last().unwrap().question, "pinned");
+    }
+
+    #[test]
+    fn sections_competing_for_a_kind_are_routed_around() {
+        // Section a can be satisfied by either of its questions, but if it takes the only
+        // multiple choice slot section b (which only has multiple choice) cannot be satisfied
+        let pool = vec![
+            mcq("a1", Some("a")),
+            short("a2", Some("a")),
+            mcq("b1", Some("b")),
+        ];
+        let bp = blueprint(1, 1, 0, &[("a", 1), ("b", 1)]);
+
+        for order in [
+            KINDS,
+            [
+                QuestionKind::Short,
+                QuestionKind::MultipleChoice,
+                QuestionKind::Long,
+            ],
+        ] {
+            let quotas = plan_sections(&bp, &pool, order).unwrap();
+
+            assert_eq!(quotas["a"], [0, 1, 0]);
+            assert_eq!(quotas["b"], [1, 0, 0]);
+        }
+
+        for _ in 0..50 {
+            let mut names = build_quiz(&bp, &pool)
+                .unwrap()
+                .into_iter()
+                .map(|q| q.question)
+                .collect::<Vec<_>>();
+            names.sort();
+
+            assert_eq!(names, ["a2", "b1"]);
+        }
+    }
+
+    #[test]
+    fn questions_are_never_picked_twice() {
+        // Sectioned questions are eligible both for their section and for the rest of the quiz
+        let pool = vec![
+            mcq("a1", Some("a")),
+            mcq("a2", Some("a")),
+            mcq("b1", Some("b")),
+            mcq("m1", None),
+        ];
+        let bp = blueprint(4, 0, 0, &[("a", 1), ("b", 1)]);
+
+        for _ in 0..50 {
+            let mut names = build_quiz(&bp, &pool)
+                .unwrap()
+                .into_iter()
+                .map(|q| q.question)
+                .collect::<Vec<_>>();
+            names.sort();
+
+            assert_eq!(names, ["a1", "a2", "b1", "m1"]);
+        }
+    }
+
+    #[test]
+    fn weights_bias_the_pick() {
+        let mut heavy = mcq("heavy", None);
+        heavy.weight = 1e9;
+
+        let mut light = mcq("light", None);
+        light.weight = 1e-9;
+
+        let pool = vec![light, heavy];
+        let bp = blueprint(1, 0, 0, &[]);
+
+        for _ in 0..200 {
+            assert_eq!(build_quiz(&bp, &pool).unwrap()[0].question, "heavy");
+        }
+    }
+
+    #[test]
+    fn non_positive_weights_are_rejected() {
+        for weight in [0.0, -1.0, f64::NAN] {
+            let mut q = mcq("m1", None);
+            q.weight = weight;
+
+            assert!(validate(&blueprint(1, 0, 0, &[]), &[q]).is_err());
+        }
+    }
+
+    #[test]
+    fn infeasible_blueprints_are_errors() {
+        // Section a asks for more questions than it has
+        let pool = vec![
+            mcq("a1", Some("a")),
+            mcq("m1", None),
+            mcq("m2", None),
+            mcq("m3", None),
+        ];
+        let bp = blueprint(2, 0, 0, &[("a", 2)]);
+
+        assert!(plan_sections(&bp, &pool, KINDS).is_err());
+        assert!(validate(&bp, &pool).is_err());
+        assert!(build_quiz(&bp, &pool).is_err());
+
+        // Section a has enough questions, but only of a kind the blueprint wants fewer of
+        let pool = vec![
+            short("a1", Some("a")),
+            short("a2", Some("a")),
+            mcq("m1", None),
+        ];
+        let bp = blueprint(1, 1, 0, &[("a", 2)]);
+
+        assert!(validate(&bp, &pool).is_err());
+        assert!(build_quiz(&bp, &pool).is_err());
+
+        // More questions of a kind than the pool has
+        let pool = vec![short("s1", None), short("s2", None)];
+        let bp = blueprint(0, 3, 0, &[]);
+
+        assert!(validate(&bp, &pool).is_err());
+        assert!(build_quiz(&bp, &pool).is_err());
+    }
+
+    #[test]
+    fn section_minimums_larger_than_the_quiz_are_errors() {
+        let pool = vec![
+            mcq("a1", Some("a")),
+            mcq("a2", Some("a")),
+            mcq("a3", Some("a")),
+        ];
+
+        let err = validate(&blueprint(2, 0, 0, &[("a", 3)]), &pool).unwrap_err();
+        assert!(err.to_string().contains("only picks 2"), "{}", err);
+
+        // Unless pinned questions make up for it
+        let mut pinned = short("pinned", Some("a"));
+        pinned.pinned = true;
+
+        let mut pool = pool;
+        pool.push(pinned);
+
+        validate(&blueprint(2, 0, 0, &[("a", 3)]), &pool).unwrap();
+    }
+
+    #[test]
+    fn unknown_tags_and_sections_are_errors() {
+        let mut tagged = mcq("m1", Some("a"));
+        tagged.tags = vec!["api".to_string()];
+
+        let pool = vec![tagged, mcq("m2", None)];
+
+        let mut bp = blueprint(1, 0, 0, &[]);
+        bp.tags = vec!["api".to_string()];
+        validate(&bp, &pool).unwrap();
+
+        bp.tags = vec!["api".to_string(), "apu".to_string()];
+        assert!(validate(&bp, &pool)
+            .unwrap_err()
+            .to_string()
+            .contains("'apu'"));
+
+        let bp = blueprint(1, 0, 0, &[("a", 1)]);
+        validate(&bp, &pool).unwrap();
+
+        let bp = blueprint(1, 0, 0, &[("b", 1)]);
+        assert!(validate(&bp, &pool)
+            .unwrap_err()
+            .to_string()
+            .contains("'b'"));
+    }
+
+    #[test]
+    fn malformed_blueprints_are_errors() {
+        let pool = vec![mcq("m1", None)];
+
+        let mut bp = blueprint(1, 0, 0, &[]);
+        bp.time_limit = Some(0);
+        assert!(bp.check().is_err());
+        assert!(validate(&bp, &pool).is_err());
+
+        let mut bp = blueprint(1, 0, 0, &[]);
+        bp.tags = vec![" ".to_string()];
+        assert!(bp.check().is_err());
+
+        assert!(blueprint(1, 0, 0, &[("", 1)]).check().is_err());
+
+        let mut bp = blueprint(1, 0, 0, &[]);
+        bp.time_limit = Some(30);
+        bp.check().unwrap();
+    }
+}
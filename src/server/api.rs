@@ -1,150 +1,465 @@
-use std::{collections::HashMap, sync::Arc, str::FromStr, fmt::Display};
+use std::{collections::HashMap, sync::Arc, str::FromStr};
 
 use axum::{
     extract::{Query, State},
-    http::StatusCode,
-    response::{IntoResponse, Redirect, Response},
-    routing::get,
-    routing::post,
+    http::{header::{COOKIE, SET_COOKIE}, HeaderMap, StatusCode},
+    middleware,
+    response::{IntoResponse, Redirect},
     Json, Router,
 };
 use log::info;
 use poise::serenity_prelude::{AddMember, GuildId};
-use rand::seq::SliceRandom;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use sqlx::{PgPool, types::uuid};
-use tower_http::cors::{Any, CorsLayer};
+use sqlx::{PgPool, types::{chrono, uuid}};
 use ts_rs::TS;
 
 use botox::cache::{member_on_guild, CacheHttpImpl};
 
 use crate::{
-    config::{self, Question, QuestionData},
+    analytics::Report,
+    config::{self, OAuthProviderKind, Question, QuestionData},
+    finish::CodeCheck,
+    history::TimelineEntry,
+    questions::{BankQuestion, QuizQuestion},
+    quiz::{
+        AnswerFeedback, GradeBreakdown, ManualGrade, QuestionTiming, QuizDraft, QuizTimings,
+        ScoreBreakdown,
+    },
     setup::{get_onboard_user_role, setup_readme},
+    similarity::SimilarityReport,
 };
 
-use super::types::{login::ConfirmLoginState, auth::{GetAuthData, CreateLogin}, oauth2::{ConfirmLogin, AccessTokenResponse}};
+use super::auth::LoginCodes;
+use super::error::{ApiErrorCode, Error};
+use super::health::{OnboardingCounts, Readiness};
+use super::openapi::{RouteDoc, Routes};
+use super::ratelimit::RateLimiter;
+use super::oauth::{DiscordProvider, OAuthProvider};
+use super::extract::{Auth, GradeOnboarding as GradeOnboardingPerm, ManageQuestions, Payload, RequirePerm, RevokeSessions, ViewOnboardingResponses, ViewReports};
+use super::types::{login::{validate_code_challenge, ConfirmLoginState, SignedLoginState, UsedLoginNonces, LOGIN_STATE_TTL_SECS}, auth::{AuthData, CreateLogin, ExchangeCode, SessionInfo, SessionTokens}, oauth2::ConfirmLogin};
 
-struct Error {
-    status: StatusCode,
-    message: String,
+pub struct AppState {
+    pub cache_http: CacheHttpImpl,
+    pub pool: PgPool,
+    pub used_login_nonces: UsedLoginNonces,
+    /// Provider logins go through
+    pub oauth: Arc<dyn OAuthProvider>,
+    /// Codes session logins redirect back with
+    pub login_codes: LoginCodes,
+    pub rate_limiter: RateLimiter,
+    pub onboarding_counts: OnboardingCounts,
 }
 
-impl Error {
-    fn new(e: impl Display) -> Self {
+impl AppState {
+    pub fn new(pool: PgPool, cache_http: CacheHttpImpl) -> Self {
+        let oauth: Arc<dyn OAuthProvider> = match config::CONFIG.oauth.provider {
+            OAuthProviderKind::Discord => Arc::new(DiscordProvider::new(
+                reqwest::Client::new(),
+                &config::CONFIG.oauth.api_url,
+            )),
+            #[cfg(any(test, feature = "mock-oauth"))]
+            OAuthProviderKind::Mock => {
+                log::warn!("Using the mock OAuth2 provider, anyone can login as any user! This must never be used in production");
+
+                Arc::new(super::mock_oauth::MockProvider::default())
+            }
+        };
+
         Self {
-            status: StatusCode::INTERNAL_SERVER_ERROR,
-            message: e.to_string(),
+            pool,
+            cache_http,
+            used_login_nonces: UsedLoginNonces::default(),
+            oauth,
+            login_codes: LoginCodes::default(),
+            rate_limiter: RateLimiter::default(),
+            onboarding_counts: OnboardingCounts::default(),
         }
     }
 }
 
-impl IntoResponse for Error {
-    fn into_response(self) -> Response {
-        (self.status, self.message).into_response()
+pub async fn setup_server(pool: PgPool, cache_http: CacheHttpImpl) {
+    if let Err(e) = super::listen::serve(app(pool, cache_http), &config::CONFIG.rpc_server).await {
+        panic!("RPC server error: {}", e);
     }
 }
 
-pub struct AppState {
-    pub cache_http: CacheHttpImpl,
-    pub pool: PgPool,
-}
+/// Builds the RPC server
+pub fn app(pool: PgPool, cache_http: CacheHttpImpl) -> Router {
+    let shared_state = Arc::new(AppState::new(pool, cache_http));
 
-pub async fn setup_server(pool: PgPool, cache_http: CacheHttpImpl) {
-    let shared_state = Arc::new(AppState { pool, cache_http });
-
-    let app = Router::new()
-        .route("/create-login", get(create_login))
-        .route("/confirm-login", get(confirm_login))
-        .route("/auth-data", post(get_auth_data))
-        .route("/onboarding-code", post(get_onboarding_code))
-        .route("/quiz", post(create_quiz))
-        .route("/onboarding-response", post(get_onboard_response))
-        .route("/submit-quiz", post(submit_onboarding))
-        .with_state(shared_state)
-        .layer(
-            CorsLayer::new()
-                .allow_origin(Any)
-                .allow_methods(Any)
-                .allow_headers(Any),
-        );
-
-    let addr = "0.0.0.0:3011".parse().expect("Invalid server address");
-
-    info!("Starting RPC server on {}", addr);
-
-    if let Err(e) = axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-    {
-        panic!("RPC server error: {}", e);
-    }
+    let cors = super::listen::cors_layer(&config::CONFIG.rpc_server).expect("Invalid CORS config");
+
+    routes(&shared_state)
+        .finish()
+        .route_layer(middleware::from_fn(super::health::track_requests))
+        .with_state(shared_state.clone())
+        .layer(middleware::from_fn_with_state(shared_state, super::ratelimit::limit_ip))
+        .layer(cors)
 }
 
+/// Route table of the RPC server, including the routes of the OAuth2 provider
+fn routes(shared_state: &Arc<AppState>) -> Routes {
+    // Routes that need a login, the login token is resolved once by the auth middleware
+    let authed = Routes::new(true)
+        .get_post(
+            "/auth-data",
+            RouteDoc::new("Returns the session of the logged in user").json::<AuthData>(),
+            get_auth_data,
+        )
+        .post(
+            "/logout",
+            RouteDoc::new("Revokes the session making the request"),
+            logout,
+        )
+        .get_post(
+            "/sessions",
+            RouteDoc::new("Lists the sessions of the logged in user").json_list::<SessionInfo>(),
+            list_sessions,
+        )
+        .post(
+            "/revoke-user-sessions",
+            RouteDoc::new("Revokes every session of a user, returning the number of revoked sessions")
+                .request::<RevokeUserSessions>()
+                .json::<u64>()
+                .permission::<RevokeSessions>(),
+            revoke_user_sessions,
+        )
+        .get_post(
+            "/onboarding-code",
+            RouteDoc::new("Returns the staff verification code shown in the staff guide")
+                .request::<GetOnboardingCode>()
+                .text()
+                .errors(&[ApiErrorCode::NotFound]),
+            get_onboarding_code,
+        )
+        .post(
+            "/quiz",
+            RouteDoc::new("Creates the quiz of an onboarding, or returns it if it was already created")
+                .request::<CreateQuizRequest>()
+                .json::<CreateQuizResponse>()
+                .errors(&[ApiErrorCode::NotFound, ApiErrorCode::InvalidState, ApiErrorCode::QuizTimeExpired]),
+            create_quiz,
+        )
+        .get_post(
+            "/onboarding-response",
+            RouteDoc::new("Returns the quiz response of an onboarding")
+                .request::<GetOnboardingResponse>()
+                .json::<OnboardResponse>()
+                .permission::<ViewOnboardingResponses>()
+                .errors(&[ApiErrorCode::NotFound]),
+            get_onboard_response,
+        )
+        .post(
+            "/submit-quiz",
+            RouteDoc::new("Submits the quiz of an onboarding along with the staff verification code")
+                .request::<SubmitOnboarding>()
+                .errors(&[
+                    ApiErrorCode::NotFound,
+                    ApiErrorCode::InvalidState,
+                    ApiErrorCode::QuizOutdated,
+                    ApiErrorCode::QuizTimeExpired,
+                    ApiErrorCode::VerifyCodeLocked,
+                    ApiErrorCode::InvalidAnswers,
+                    ApiErrorCode::IncorrectVerifyCode,
+                ]),
+            submit_onboarding,
+        )
+        .post(
+            "/save-quiz-draft",
+            RouteDoc::new("Saves the answers of a quiz in progress, returning feedback on them")
+                .request::<SaveQuizDraft>()
+                .json_list::<AnswerFeedback>()
+                .errors(&[
+                    ApiErrorCode::NotFound,
+                    ApiErrorCode::InvalidState,
+                    ApiErrorCode::QuizOutdated,
+                    ApiErrorCode::QuizTimeExpired,
+                ]),
+            save_quiz_draft,
+        )
+        .get_post(
+            "/onboarding-timeline",
+            RouteDoc::new("Returns the state transitions of an onboarding")
+                .request::<GetOnboardingTimeline>()
+                .json_list::<TimelineEntry>()
+                .permission::<ViewOnboardingResponses>(),
+            get_onboarding_timeline,
+        )
+        .get_post(
+            "/onboarding-report",
+            RouteDoc::new(
+                "Returns funnel, timing, verdict and question analytics of the onboardings created in a range",
+            )
+                .request::<GetOnboardingReport>()
+                .json::<Report>()
+                .permission::<ViewReports>(),
+            get_onboarding_report,
+        )
+        .post(
+            "/grade-onboarding",
+            RouteDoc::new("Grades the quiz response of an onboarding")
+                .request::<GradeOnboarding>()
+                .json::<GradeBreakdown>()
+                .permission::<GradeOnboardingPerm>()
+                .errors(&[ApiErrorCode::NotFound, ApiErrorCode::InvalidState]),
+            grade_onboarding,
+        )
+        .get_post(
+            "/list-questions",
+            RouteDoc::new("Lists the questions of the question bank")
+                .request::<ListQuestions>()
+                .json_list::<BankQuestion>()
+                .permission::<ManageQuestions>(),
+            list_questions,
+        )
+        .get_post(
+            "/question-revisions",
+            RouteDoc::new("Lists every revision of a question")
+                .request::<GetQuestionRevisions>()
+                .json_list::<BankQuestion>()
+                .permission::<ManageQuestions>(),
+            get_question_revisions,
+        )
+        .post(
+            "/create-question",
+            RouteDoc::new("Adds a question to the question bank, returning its id")
+                .request::<CreateQuestion>()
+                .text()
+                .permission::<ManageQuestions>(),
+            create_question,
+        )
+        .post(
+            "/update-question",
+            RouteDoc::new("Updates a question, returning the new revision")
+                .request::<UpdateQuestion>()
+                .json::<i32>()
+                .permission::<ManageQuestions>()
+                .errors(&[ApiErrorCode::NotFound]),
+            update_question,
+        )
+        .post(
+            "/delete-question",
+            RouteDoc::new("Removes a question from the question bank")
+                .request::<DeleteQuestion>()
+                .permission::<ManageQuestions>()
+                .errors(&[ApiErrorCode::NotFound]),
+            delete_question,
+        )
+        .map_router(|router| {
+            // Route layers run in reverse order, users are only known once the auth middleware has run
+            router
+                .route_layer(middleware::from_fn_with_state(shared_state.clone(), super::ratelimit::limit_user))
+                .route_layer(middleware::from_fn_with_state(shared_state.clone(), super::extract::require_auth))
+        });
+
+    Routes::new(false)
+        .get(
+            "/create-login",
+            RouteDoc::new("Signs a login state and redirects to the OAuth2 provider")
+                .request::<CreateLogin>()
+                .redirect(),
+            create_login,
+        )
+        .get(
+            "/confirm-login",
+            RouteDoc::new("OAuth2 callback, redirects to the onboarding server or back to the panel with a login code")
+                .request::<ConfirmLogin>()
+                .redirect()
+                .errors(&[ApiErrorCode::LoginFailed, ApiErrorCode::Forbidden, ApiErrorCode::NotFound]),
+            confirm_login,
+        )
+        .post(
+            "/exchange-code",
+            RouteDoc::new("Exchanges the code a login redirected back with for session tokens")
+                .request::<ExchangeCode>()
+                .json::<SessionTokens>()
+                .errors(&[ApiErrorCode::LoginFailed]),
+            exchange_code,
+        )
+        .post(
+            "/refresh-session",
+            RouteDoc::new("Issues new session tokens, the refresh token can only be used once")
+                .request::<RefreshSession>()
+                .json::<SessionTokens>()
+                .errors(&[ApiErrorCode::IdentityExpired]),
+            refresh_session,
+        )
+        .get(
+            "/healthz",
+            RouteDoc::new("Succeeds while the RPC server is running").text(),
+            super::health::healthz,
+        )
+        .get(
+            "/readyz",
+            RouteDoc::new("Checks the database, gateway and cache, responding with 503 if any are not ready")
+                .json::<Readiness>(),
+            super::health::readyz,
+        )
+        .get(
+            "/metrics",
+            RouteDoc::new("Metrics in the Prometheus text format, requires the metrics token as a bearer token")
+                .text()
+                .errors(&[ApiErrorCode::Forbidden, ApiErrorCode::NotFound]),
+            super::health::metrics,
+        )
+        .merge(shared_state.oauth.clone().routes())
+        .merge(authed)
+}
 
-async fn get_auth_data(State(app_state): State<Arc<AppState>>, Json(gad): Json<GetAuthData>) -> Result<impl IntoResponse, Error> {
-    let auth_data = super::auth::check_auth(
-        &app_state.pool,
-        &gad.login_token,
-    )
-    .await
-    .map_err(Error::new)?;
 
+async fn get_auth_data(Auth(auth_data): Auth) -> Result<impl IntoResponse, Error> {
     Ok(Json(auth_data))
 }
 
-async fn create_login(State(app_state): State<Arc<AppState>>, Query(cl): Query<CreateLogin>) -> Result<impl IntoResponse, Error> {
-    let state = ConfirmLoginState::from_str(&cl.state).map_err(Error::new)?;
-        Ok(Redirect::temporary(&state.make_login_url(&app_state.cache_http.cache.current_user().id.to_string())).into_response())
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/RefreshSession.ts")]
+struct RefreshSession {
+    refresh_token: String,
 }
 
-async fn confirm_login(
+async fn refresh_session(
     State(app_state): State<Arc<AppState>>,
-    Query(data): Query<ConfirmLogin>,
+    Payload(req): Payload<RefreshSession>,
+) -> Result<Json<SessionTokens>, Error> {
+    let session = super::auth::refresh_session(&app_state.pool, &req.refresh_token)
+        .await
+        .map_err(|e| Error::Internal(format!("Could not refresh session: {}", e)))?
+        .ok_or(Error::IdentityExpired)?;
+
+    Ok(Json(session))
+}
+
+/// Revokes the session making the request
+async fn logout(
+    State(app_state): State<Arc<AppState>>,
+    Auth(auth_data): Auth,
 ) -> Result<impl IntoResponse, Error> {
-    let state = ConfirmLoginState::from_str(data.state.as_str()).map_err(|_| Error::new("Invalid state"))?;
-
-    // Create access token from code
-    let client = reqwest::Client::new();
-
-    let access_token = client
-        .post("https://discord.com/api/v10/oauth2/token")
-        .form(&json!({
-            "client_id": app_state.cache_http.cache.current_user().id.to_string(),
-            "client_secret": config::CONFIG.client_secret,
-            "grant_type": "authorization_code",
-            "code": data.code,
-            "redirect_uri": format!("{}/confirm-login", config::CONFIG.persepolis_domain),
-        }))
-        .send()
+    let session_id = uuid::Uuid::from_str(&auth_data.session_id)
+        .map_err(|_| Error::Internal("Invalid session id".to_string()))?;
+
+    super::auth::revoke_session(&app_state.pool, session_id)
         .await
-        .map_err(|_| Error::new("Could not send request to get access token".to_string()))?
-        .error_for_status()
-        .map_err(|e| Error::new(format!("Could not get access token: {}", e)))?;
+        .map_err(|e| Error::Internal(format!("Could not revoke session: {}", e)))?;
 
-    let access_token = access_token
-        .json::<AccessTokenResponse>()
+    Ok((StatusCode::NO_CONTENT).into_response())
+}
+
+async fn list_sessions(
+    State(app_state): State<Arc<AppState>>,
+    Auth(auth_data): Auth,
+) -> Result<Json<Vec<SessionInfo>>, Error> {
+    let sessions = super::auth::list_sessions(&app_state.pool, &auth_data.user_id, &auth_data.session_id)
         .await
-        .map_err(|_| Error::new("Could not deserialize response".to_string()))?;
-
-    // Get user from access token
-    let user = client
-        .get("https://discord.com/api/v10/users/@me")
-        .header(
-            "Authorization",
-            format!("Bearer {}", access_token.access_token),
-        )
-        .send()
+        .map_err(|e| Error::Internal(format!("Could not get sessions: {}", e)))?;
+
+    Ok(Json(sessions))
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/RevokeUserSessions.ts")]
+struct RevokeUserSessions {
+    user_id: String,
+}
+
+/// Revokes every session of a user, returning the number of revoked sessions
+async fn revoke_user_sessions(
+    State(app_state): State<Arc<AppState>>,
+    _: RequirePerm<RevokeSessions>,
+    Payload(req): Payload<RevokeUserSessions>,
+) -> Result<Json<u64>, Error> {
+    let count = super::auth::revoke_user_sessions(&app_state.pool, &req.user_id)
         .await
-        .map_err(|_| Error::new("Could not send request to get user".to_string()))?
-        .error_for_status()
-        .map_err(|_| Error::new("Get User failed!".to_string()))?;
+        .map_err(|e| Error::Internal(format!("Could not revoke sessions: {}", e)))?;
+
+    Ok(Json(count))
+}
+
+/// Exchanges the code a login redirected back with for session tokens
+async fn exchange_code(
+    State(app_state): State<Arc<AppState>>,
+    Json(req): Json<ExchangeCode>,
+) -> Result<impl IntoResponse, Error> {
+    let user_id = app_state
+        .login_codes
+        .redeem(&req.code, req.code_verifier.as_deref())?;
+
+    info!("Creating session for {}", user_id);
 
-    let user = user
-        .json::<serenity::model::user::User>()
+    let session = super::auth::create_session(&app_state.pool, &user_id)
         .await
-        .map_err(|_| Error::new("Could not deserialize response".to_string()))?;
+        .map_err(|e| Error::Internal(format!("Could not create session: {}", e)))?;
+
+    Ok(Json(session))
+}
+
+/// Cookie the login state is bound to, set when the login is started
+const LOGIN_BINDING_COOKIE: &str = "persepolis_login";
+
+async fn create_login(State(app_state): State<Arc<AppState>>, Query(cl): Query<CreateLogin>) -> Result<impl IntoResponse, Error> {
+    let mut state = ConfirmLoginState::from_str(&cl.state).map_err(|e| Error::InvalidRequest(e.to_string()))?;
+
+    if let ConfirmLoginState::CreateSession(url, code_challenge) = &mut state {
+        // Reject bad redirects before sending the user through the OAuth2 provider
+        if config::CONFIG.allowed_redirect(url).is_none() {
+            return Err(Error::InvalidRequest("Invalid url".to_string()));
+        }
+
+        if let Some(challenge) = cl.code_challenge {
+            validate_code_challenge(&challenge).map_err(|e| Error::InvalidRequest(e.to_string()))?;
+
+            *code_challenge = Some(challenge);
+        }
+    }
+
+    let binding = botox::crypto::gen_random(32);
+
+    let url = state.make_login_url(
+        app_state.oauth.as_ref(),
+        &app_state.cache_http.cache.current_user().id.to_string(),
+        Some(&binding),
+    );
+
+    let cookie = format!(
+        "{}={}; Path=/confirm-login; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        LOGIN_BINDING_COOKIE,
+        binding,
+        LOGIN_STATE_TTL_SECS,
+        if config::CONFIG.persepolis_domain.starts_with("https://") { "; Secure" } else { "" }
+    );
+
+    Ok(([(SET_COOKIE, cookie)], Redirect::temporary(&url)).into_response())
+}
+
+async fn confirm_login(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(data): Query<ConfirmLogin>,
+) -> Result<impl IntoResponse, Error> {
+    let cookie = headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .find_map(|c| c.trim().strip_prefix(LOGIN_BINDING_COOKIE)?.strip_prefix('='));
+
+    let signed = SignedLoginState::verify(&data.state, cookie)
+        .map_err(|e| Error::InvalidRequest(e.to_string()))?;
+
+    // States can only be used once
+    if !app_state.used_login_nonces.mark_used(&signed) {
+        return Err(Error::InvalidRequest("This login link has already been used, please try again".to_string()));
+    }
+
+    let state = signed.state;
+
+    let access_token = app_state
+        .oauth
+        .exchange_code(&app_state.cache_http.cache.current_user().id.to_string(), &data.code)
+        .await?;
+
+    let user = app_state.oauth.get_user(&access_token.access_token).await?;
 
     // Check if staff member or awaiting staff
     let row = sqlx::query!(
@@ -153,7 +468,7 @@ async fn confirm_login(
     )
     .fetch_optional(&app_state.pool)
     .await
-    .map_err(|_| Error::new("Could not get staff member data from database".to_string()))?;
+    .map_err(|_| Error::Internal("Could not get staff member data from database".to_string()))?;
 
     let is_staff = {
         if row.is_some() && !row.unwrap().positions.is_empty() {
@@ -166,7 +481,7 @@ async fn confirm_login(
                 false
             )
             .await
-            .map_err(|e| Error::new(format!("Failed to fetch member: {:#?}", e)))?;
+            .map_err(|e| Error::Internal(format!("Failed to fetch member: {:#?}", e)))?;
 
             if let Some(member) = member {
                 member
@@ -179,7 +494,7 @@ async fn confirm_login(
     };
 
     if !is_staff {
-        return Err(Error::new("You are not a staff member or awaiting staff"));
+        return Err(Error::Forbidden("You are not a staff member or awaiting staff".to_string()));
     }
 
     match state {
@@ -188,39 +503,40 @@ async fn confirm_login(
                 // Check if admin
                 let perms = crate::perms::get_user_perms(&app_state.pool, &user.id.to_string())
                     .await
-                    .map_err(|e| Error::new(format!("Could not get user perms: {}", e)))?
+                    .map_err(|e| Error::Internal(format!("Could not get user perms: {}", e)))?
                     .resolve();
         
                 if !kittycat::perms::has_perm(&perms, &kittycat::perms::build("persepolis", "join_onboarding_servers")) {
                     return Err(
-                        Error::new("Only staff members with the `persepolis.join_onboarding_servers` permission and the user themselves can join onboarding servers")
+                        Error::Forbidden("Only staff members with the `persepolis.join_onboarding_servers` permission and the user themselves can join onboarding servers".to_string())
                     );
                 }
             }
         
             if !access_token.scope.contains("guilds.join") {
                 return Err(
-                    Error::new("Invalid scope. Scope must be exactly contain guilds.join"),
+                    Error::LoginFailed("Invalid scope. Scope must be exactly contain guilds.join".to_string()),
                 );
             }
         
             let guild_id = sqlx::query!(
-                "SELECT guild_id FROM staff_onboardings WHERE user_id = $1 AND state != $2 ORDER BY created_at DESC LIMIT 1",
+                "SELECT guild_id FROM staff_onboardings WHERE user_id = $1 AND state != $2 AND state != $3 ORDER BY created_at DESC LIMIT 1",
                 uid.to_string(),
-                crate::states::OnboardState::Completed.to_string()
+                crate::states::OnboardState::Completed.to_string(),
+                crate::states::OnboardState::Expired.to_string()
             )
             .fetch_one(&app_state.pool)
             .await
-            .map_err(|_| Error::new("Could not get any pending onboarding guilds for you from database"))?;
+            .map_err(|e| Error::query(e, "Could not get any pending onboarding guilds for you from database"))?;
         
             let guild_id = guild_id.guild_id.parse::<GuildId>().map_err(|e| {
-                Error::new(
+                Error::Internal(
                     format!("Could not parse guild id {}", e)
                 )
             })?;
             let channel_id = setup_readme(&app_state.cache_http, guild_id)
                 .await
-                .map_err(|_| Error::new("Could not create invite"))?;
+                .map_err(|_| Error::Internal("Could not create invite".to_string()))?;
         
             let guild_url = format!("https://discord.com/channels/{}/{}", guild_id, channel_id);
         
@@ -232,7 +548,7 @@ async fn confirm_login(
                 false
             )
             .await
-            .map_err(|e| Error::new(format!("Failed to fetch member: {:#?}", e)))?
+            .map_err(|e| Error::Internal(format!("Failed to fetch member: {:#?}", e)))?
             .is_some() {
                 Ok(Redirect::temporary(&guild_url).into_response())
             } else {
@@ -240,7 +556,7 @@ async fn confirm_login(
                 let roles = if user.id == uid {
                     vec![get_onboard_user_role(&app_state.cache_http, guild_id)
                         .await
-                        .map_err(Error::new)?]
+                        .map_err(|e| Error::Internal(e.to_string()))?]
                 } else {
                     vec![]
                 };
@@ -253,7 +569,7 @@ async fn confirm_login(
                     )
                     .await
                     .map_err(|err| {
-                        Error::new(
+                        Error::Internal(
                             format!("Could not add user to guild: {}", err)
                         )
                     })?;
@@ -261,64 +577,43 @@ async fn confirm_login(
                 Ok(Redirect::temporary(&guild_url).into_response())
             }
         }
-        ConfirmLoginState::CreateSession(url) => {  
-            if !url.starts_with(&crate::config::CONFIG.panel_url) && !url.starts_with("http://localhost") {
-                return Err(Error::new("Invalid url".to_string()));
-            }
-     
-            info!("Creating session for {}", user.id.to_string());     
-            // Create a random number between 4196 and 6000 for the token
-            let token = botox::crypto::gen_random(512);
-
-            sqlx::query!(
-                "INSERT INTO staffpanel__authchain (user_id, token, popplio_token, state) VALUES ($1, $2, $3, $4)",
-                user.id.to_string(),
-                token,
-                botox::crypto::gen_random(2048),
-                "persepolis.active"
-            )
-            .execute(&app_state.pool)
-            .await
-            .map_err(|_| Error::new("Could not create session".to_string()))?;
+        ConfirmLoginState::CreateSession(url, code_challenge) => {  
+            let Some(mut url) = config::CONFIG.allowed_redirect(&url) else {
+                return Err(Error::InvalidRequest("Invalid url".to_string()));
+            };
 
-            Ok(Redirect::temporary(
-                &format!(
-                    "{}?token={}",
-                    url,
-                    token
-                )
-            ).into_response())
+            // The session is only created once the code is exchanged so no token ends up in
+            // browser history or referer headers
+            let code = app_state.login_codes.issue(&user.id.to_string(), code_challenge);
+
+            url.query_pairs_mut().append_pair("code", &code);
+
+            Ok(Redirect::temporary(url.as_str()).into_response())
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, TS)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
 #[ts(export, export_to = ".generated/GetOnboardingCode.ts")]
 struct GetOnboardingCode {
-    login_token: String,
     id: String,
 }
 
 #[axum_macros::debug_handler]
 async fn get_onboarding_code(
     State(app_state): State<Arc<AppState>>,
-    Json(get_onboarding_code_req): Json<GetOnboardingCode>,
+    Auth(auth_data): Auth,
+    Payload(get_onboarding_code_req): Payload<GetOnboardingCode>,
 ) -> Result<impl IntoResponse, Error> {
-    let auth_data = super::auth::check_auth(
-        &app_state.pool,
-        &get_onboarding_code_req.login_token,
-    )
-    .await
-    .map_err(Error::new)?;
 
     let mut tx = app_state
         .pool
         .begin()
         .await
-        .map_err(|_| Error::new("Could not start transaction".to_string()))?;
+        .map_err(|_| Error::Internal("Could not start transaction".to_string()))?;
 
     let uuid = sqlx::types::uuid::Uuid::from_str(&get_onboarding_code_req.id)
-        .map_err(|_| Error::new("Invalid id".to_string()))?;
+        .map_err(|_| Error::InvalidRequest("Invalid id".to_string()))?;
 
     let rec = sqlx::query!(
         "SELECT user_id, staff_verify_code FROM staff_onboardings WHERE id = $1 AND user_id = $2",
@@ -327,13 +622,13 @@ async fn get_onboarding_code(
     )
     .fetch_one(&mut *tx)
     .await
-    .map_err(|_| Error::new("Could not find onboarding response".to_string()))?;
+    .map_err(|e| Error::query(e, "Could not find onboarding response"))?;
 
-    let code = if let Some(code) = rec.staff_verify_code {
-        code
+    let seed = if let Some(seed) = rec.staff_verify_code {
+        seed
     } else {
-        // Generate 76 character random string for onboard code
-        let onboard_code = botox::crypto::gen_random(76);
+        // Each onboarding gets its own seed, so codes are never reused between onboardings
+        let onboard_code = crate::finish::new_code_seed();
 
         // Set onboard code for user
         sqlx::query!(
@@ -344,19 +639,25 @@ async fn get_onboarding_code(
         )
         .execute(&mut *tx)
         .await
-        .map_err(|_| Error::new("Could not set onboard code".to_string()))?;
+        .map_err(|_| Error::Internal("Could not set onboard code".to_string()))?;
 
         onboard_code
     };
 
     tx.commit()
         .await
-        .map_err(|_| Error::new("Could not commit transaction".to_string()))?;
+        .map_err(|_| Error::Internal("Could not commit transaction".to_string()))?;
+
+    let code = crate::finish::guide_code(
+        &uuid.hyphenated().to_string(),
+        &auth_data.user_id,
+        &seed,
+    );
 
     Ok(code.into_response())
 }
 
-#[derive(Serialize, Deserialize, Clone, TS)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
 #[ts(export, export_to = ".generated/Verdict.ts")]
 pub struct Verdict {
     pub action: String,
@@ -364,72 +665,108 @@ pub struct Verdict {
     pub end_review_time: i64
 }
 
-#[derive(Serialize, Deserialize, Clone, TS)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
 #[ts(export, export_to = ".generated/OnboardResponse.ts")]
 struct OnboardResponse {
     user_id: String,
-    questions: Option<Vec<Question>>,
+    questions: Option<Vec<QuizQuestion>>,
     answers: Option<HashMap<String, String>>,
     verdict: Option<Verdict>,
+    score: Option<ScoreBreakdown>,
+    grades: Option<GradeBreakdown>,
+    timings: Option<QuizTimings>,
+    similarity: Option<SimilarityReport>,
     created_at: i64,
     finished_at: Option<i64>,
 }
 
-#[derive(Serialize, Deserialize, Clone, TS)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
 #[ts(export, export_to = ".generated/GetOnboardingResponse.ts")]
 struct GetOnboardingResponse {
-    login_token: String,
     id: String,
 }
 
 async fn get_onboard_response(
     State(app_state): State<Arc<AppState>>,
-    Json(req): Json<GetOnboardingResponse>,
+    _: RequirePerm<ViewOnboardingResponses>,
+    Payload(req): Payload<GetOnboardingResponse>,
 ) -> Result<Json<OnboardResponse>, Error> {
-    let auth_data = super::auth::check_auth(
-        &app_state.pool,
-        &req.login_token,
-    )
-    .await
-    .map_err(Error::new)?;
-
-    let user_perms = crate::perms::get_user_perms(&app_state.pool, &auth_data.user_id)
-        .await
-        .map_err(|e| Error::new(format!("Could not get user perms: {}", e)))?
-        .resolve();
-
-    if !kittycat::perms::has_perm(&user_perms, &kittycat::perms::build("persepolis", "view_onboarding_responses")) {
-        return Err(Error::new("You do not have permission to view onboarding responses".to_string()));
-    }
 
     let uuid = uuid::Uuid::from_str(&req.id)
-        .map_err(|_| Error::new("Invalid id".to_string()))?;
+        .map_err(|_| Error::InvalidRequest("Invalid id".to_string()))?;
 
     let resp = sqlx::query!(
-        "SELECT verdict, questions, answers, created_at, finished_at, user_id FROM staff_onboardings WHERE id = $1",
+        "SELECT verdict, questions, answers, score, grades, quiz_timings, similarity, created_at, finished_at, user_id FROM staff_onboardings WHERE id = $1",
         uuid
     )
     .fetch_one(&app_state.pool)
     .await
-    .map_err(|_| Error::new("Could not find onboarding response".to_string()))?;
+    .map_err(|e| Error::query(e, "Could not find onboarding response"))?;
 
     let questions = if let Some(questions) = resp.questions {
-        Some(serde_json::from_value::<Vec<Question>>(questions)
-            .map_err(|_| Error::new("Could not parse questions".to_string()))?)
+        let mut questions = serde_json::from_value::<Vec<QuizQuestion>>(questions)
+            .map_err(|_| Error::Internal("Could not parse questions".to_string()))?;
+
+        // Resolve questions to the exact revision that was asked
+        for q in questions.iter_mut() {
+            let (Some(id), Some(revision)) = (&q.id, q.revision) else {
+                continue;
+            };
+
+            let id = uuid::Uuid::from_str(id)
+                .map_err(|_| Error::InvalidRequest("Invalid question id".to_string()))?;
+
+            if let Some(question) = crate::questions::get_revision(&app_state.pool, id, revision)
+                .await
+                .map_err(|e| Error::Internal(format!("Could not get question revision: {}", e)))?
+            {
+                q.question = question;
+            }
+        }
+
+        Some(questions)
     } else {
         None
     };
 
     let answers = if let Some(answers) = resp.answers {
         Some(serde_json::from_value::<HashMap<String, String>>(answers)
-            .map_err(|_| Error::new("Could not parse answers".to_string()))?)
+            .map_err(|_| Error::Internal("Could not parse answers".to_string()))?)
     } else {
         None
     };
 
     let verdict = if let Some(verdict) = resp.verdict {
         Some(serde_json::from_value::<Verdict>(verdict)
-            .map_err(|_| Error::new("Could not parse verdict".to_string()))?)
+            .map_err(|_| Error::Internal("Could not parse verdict".to_string()))?)
+    } else {
+        None
+    };
+
+    let score = if let Some(score) = resp.score {
+        Some(serde_json::from_value::<ScoreBreakdown>(score)
+            .map_err(|_| Error::Internal("Could not parse score".to_string()))?)
+    } else {
+        None
+    };
+
+    let grades = if let Some(grades) = resp.grades {
+        Some(serde_json::from_value::<GradeBreakdown>(grades)
+            .map_err(|_| Error::Internal("Could not parse grades".to_string()))?)
+    } else {
+        None
+    };
+
+    let timings = if let Some(timings) = resp.quiz_timings {
+        Some(serde_json::from_value::<QuizTimings>(timings)
+            .map_err(|_| Error::Internal("Could not parse timings".to_string()))?)
+    } else {
+        None
+    };
+
+    let similarity = if let Some(similarity) = resp.similarity {
+        Some(serde_json::from_value::<SimilarityReport>(similarity)
+            .map_err(|_| Error::Internal("Could not parse similarity".to_string()))?)
     } else {
         None
     };
@@ -439,59 +776,233 @@ async fn get_onboard_response(
         questions,
         answers,
         verdict,
+        score,
+        grades,
+        timings,
+        similarity,
         created_at: resp.created_at.timestamp(),
         finished_at: resp.finished_at.map(|t| t.timestamp()),
     }))
 }
 
-#[derive(Serialize, Deserialize, Clone, TS)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/GradeQuestion.ts")]
+struct GradeQuestion {
+    question: String,
+    points: u32,
+    comment: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/GradeOnboarding.ts")]
+struct GradeOnboarding {
+    id: String,
+    /// Grades to add or replace, questions not in here keep their existing grade
+    grades: Vec<GradeQuestion>,
+}
+
+async fn grade_onboarding(
+    State(app_state): State<Arc<AppState>>,
+    RequirePerm(auth_data, _): RequirePerm<GradeOnboardingPerm>,
+    Payload(req): Payload<GradeOnboarding>,
+) -> Result<Json<GradeBreakdown>, Error> {
+
+    let uuid = uuid::Uuid::from_str(&req.id)
+        .map_err(|_| Error::InvalidRequest("Invalid id".to_string()))?;
+
+    let mut tx = app_state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| Error::Internal("Could not start transaction".to_string()))?;
+
+    let rec = sqlx::query!(
+        "SELECT state, questions, score, grades FROM staff_onboardings WHERE id = $1 AND void = false FOR UPDATE",
+        uuid
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| Error::query(e, "Could not find onboarding response"))?;
+
+    if rec.state != crate::states::OnboardState::PendingManagerReview.to_string() {
+        return Err(Error::InvalidState("Only onboardings pending manager review can be graded".to_string()));
+    }
+
+    let questions = serde_json::from_value::<Vec<Question>>(rec.questions.unwrap_or(json!([])))
+        .map_err(|_| Error::Internal("Could not parse questions".to_string()))?;
+
+    let score = if let Some(score) = rec.score {
+        serde_json::from_value::<ScoreBreakdown>(score)
+            .map_err(|_| Error::Internal("Could not parse score".to_string()))?
+    } else {
+        ScoreBreakdown::default()
+    };
+
+    let mut grades = if let Some(grades) = rec.grades {
+        serde_json::from_value::<GradeBreakdown>(grades)
+            .map_err(|_| Error::Internal("Could not parse grades".to_string()))?
+            .questions
+    } else {
+        vec![]
+    };
+
+    for grade in req.grades {
+        let question = questions
+            .iter()
+            .find(|q| q.question == grade.question)
+            .ok_or_else(|| Error::InvalidRequest(format!("Question '{}' is not part of this onboarding", grade.question)))?;
+
+        if !crate::quiz::needs_manual_grading(question) {
+            return Err(Error::InvalidRequest(format!("Question '{}' is graded automatically", grade.question)));
+        }
+
+        let max_points = question.points.unwrap_or(1);
+
+        if grade.points > max_points {
+            return Err(Error::InvalidRequest(format!("Question '{}' is worth at most {} points", grade.question, max_points)));
+        }
+
+        grades.retain(|g| g.question != grade.question);
+        grades.push(ManualGrade {
+            question: grade.question,
+            points: grade.points,
+            max_points,
+            comment: grade.comment,
+            graded_by: auth_data.user_id.clone(),
+            graded_at: chrono::Utc::now().timestamp(),
+        });
+    }
+
+    let breakdown = GradeBreakdown::new(&questions, &score, grades);
+
+    sqlx::query!(
+        "UPDATE staff_onboardings SET grades = $1 WHERE id = $2",
+        serde_json::to_value(&breakdown)
+            .map_err(|_| Error::Internal("Could not serialize grades".to_string()))?,
+        uuid
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| Error::Internal("Could not save grades".to_string()))?;
+
+    tx.commit()
+        .await
+        .map_err(|_| Error::Internal("Could not commit transaction".to_string()))?;
+
+    Ok(Json(breakdown))
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/GetOnboardingTimeline.ts")]
+struct GetOnboardingTimeline {
+    user_id: String,
+    /// Only return the timeline of this onboarding
+    id: Option<String>,
+}
+
+async fn get_onboarding_timeline(
+    State(app_state): State<Arc<AppState>>,
+    _: RequirePerm<ViewOnboardingResponses>,
+    Payload(req): Payload<GetOnboardingTimeline>,
+) -> Result<Json<Vec<TimelineEntry>>, Error> {
+
+    let uuid = if let Some(id) = req.id {
+        Some(uuid::Uuid::from_str(&id)
+            .map_err(|_| Error::InvalidRequest("Invalid id".to_string()))?)
+    } else {
+        None
+    };
+
+    let timeline = crate::history::get_timeline(&app_state.pool, &req.user_id, uuid)
+        .await
+        .map_err(|e| Error::Internal(format!("Could not get onboarding timeline: {}", e)))?;
+
+    Ok(Json(timeline))
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/GetOnboardingReport.ts")]
+struct GetOnboardingReport {
+    /// Unix timestamp (seconds) the range starts at
+    from: i64,
+    /// Unix timestamp (seconds) the range ends at, exclusive
+    to: i64,
+}
+
+async fn get_onboarding_report(
+    State(app_state): State<Arc<AppState>>,
+    _: RequirePerm<ViewReports>,
+    Payload(req): Payload<GetOnboardingReport>,
+) -> Result<Json<Report>, Error> {
+    let (Some(from), Some(to)) = (
+        chrono::DateTime::from_timestamp(req.from, 0),
+        chrono::DateTime::from_timestamp(req.to, 0),
+    ) else {
+        return Err(Error::InvalidRequest("Invalid date range".to_string()));
+    };
+
+    if from >= to {
+        return Err(Error::InvalidRequest("from must be before to".to_string()));
+    }
+
+    let report = crate::analytics::report(&app_state.pool, from, to)
+        .await
+        .map_err(|e| Error::Internal(format!("Could not build report: {}", e)))?;
+
+    Ok(Json(report))
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
 #[ts(export, export_to = ".generated/CreateQuizRequest.ts")] 
 struct CreateQuizRequest {
-    login_token: String,
     id: String,
 }
 
-#[derive(Serialize, Deserialize, Clone, TS)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
 #[ts(export, export_to = ".generated/PublicQuestion.ts")]
 pub struct PublicQuestion {
     pub question: String,
     pub data: QuestionData,
     pub pinned: bool, // Whether or not the question should be pinned/always present in the quiz
+    pub section: Option<String>,
 }
 
-#[derive(Serialize, TS)]
+#[derive(Serialize, JsonSchema, TS)]
 #[ts(export, export_to = ".generated/CreateQuizResponse.ts")]
 struct CreateQuizResponse {
     questions: Vec<PublicQuestion>,
     cached: bool,
+    /// Identifies this quiz, must be sent back when saving a draft
+    cache_nonce: String,
+    /// Previously saved draft answers of this quiz, if any
+    draft: Option<HashMap<String, String>>,
+    /// When the quiz was started
+    started_at: i64,
+    /// When the quiz must be submitted by, if it has a time limit
+    expires_at: Option<i64>,
 }
 
 #[axum_macros::debug_handler]
 async fn create_quiz(
     State(app_state): State<Arc<AppState>>,
-    Json(create_quiz_req): Json<CreateQuizRequest>,
+    Auth(auth_data): Auth,
+    Payload(create_quiz_req): Payload<CreateQuizRequest>,
 ) -> Result<Json<CreateQuizResponse>, Error> {
-    let auth_data = super::auth::check_auth(
-        &app_state.pool,
-        &create_quiz_req.login_token,
-    )
-    .await
-    .map_err(Error::new)?;
 
     let o_id = uuid::Uuid::from_str(&create_quiz_req.id)
-        .map_err(|_| Error::new("Invalid id".to_string()))?;
+        .map_err(|_| Error::InvalidRequest("Invalid id".to_string()))?;
 
     let rec = sqlx::query!(
-        "SELECT state, guild_id, questions FROM staff_onboardings WHERE id = $1 AND user_id = $2 AND void = false",
+        "SELECT state, guild_id, questions, quiz_draft, quiz_started_at FROM staff_onboardings WHERE id = $1 AND user_id = $2 AND void = false",
         o_id,
         auth_data.user_id
     )
     .fetch_one(&app_state.pool)
     .await
-    .map_err(|_| Error::new("Could not find onboarding response".to_string()))?;
+    .map_err(|e| Error::query(e, "Could not find onboarding response"))?;
 
     if rec.state != crate::states::OnboardState::InQuiz.to_string() {
-        return Err(Error::new(
+        return Err(Error::InvalidState(
             "Paradise Protection Protocol is not enabled right now".to_string(),
         ));
     }
@@ -507,6 +1018,42 @@ async fn create_quiz(
         .unwrap_or(0);
 
     if quiz_ver == 1 {
+        let started_at = match rec.quiz_started_at {
+            Some(started_at) => started_at.timestamp(),
+            None => {
+                // Quiz was created before time limits, start the clock now
+                sqlx::query!(
+                    "UPDATE staff_onboardings SET quiz_started_at = NOW() WHERE id = $1 RETURNING quiz_started_at",
+                    o_id
+                )
+                .fetch_one(&app_state.pool)
+                .await
+                .map_err(|_| Error::Internal("Could not start quiz".to_string()))?
+                .quiz_started_at
+                .map(|t| t.timestamp())
+                .unwrap_or_else(|| chrono::Utc::now().timestamp())
+            }
+        };
+
+        let expires_at = crate::quiz::quiz_deadline(&config::CONFIG.quiz, started_at);
+
+        if expires_at.is_some_and(|e| e < chrono::Utc::now().timestamp()) {
+            return Err(Error::QuizTimeExpired);
+        }
+
+        let cache_nonce = questions
+            .get("cache_nonce")
+            .and_then(|n| n.as_str())
+            .unwrap_or_default()
+            .to_string();
+
+        // Only return the draft if it belongs to this quiz
+        let draft = rec
+            .quiz_draft
+            .and_then(|d| serde_json::from_value::<QuizDraft>(d).ok())
+            .filter(|d| d.cache_nonce == cache_nonce)
+            .map(|d| d.answers);
+
         let obj = json!([]);
         let quiz_qvals = questions.get("questions").unwrap_or(&obj).as_array();
 
@@ -516,7 +1063,7 @@ async fn create_quiz(
             for q in question_vals {
                 // Parse question as Question
                 let question: PublicQuestion = serde_json::from_value(q.clone()).map_err(|_| {
-                    Error::new("Fatal error: Could not parse question".to_string())
+                    Error::Internal("Fatal error: Could not parse question".to_string())
                 })?;
 
                 questions.push(question);
@@ -525,87 +1072,52 @@ async fn create_quiz(
             return Ok(Json(CreateQuizResponse {
                 questions,
                 cached: true,
+                cache_nonce,
+                draft,
+                started_at,
+                expires_at,
             }));
         }
     }
 
-    // Create questions randomly from config.questions
-    let mut final_questions = vec![];
-
-    // This is in a seperate block to ensure RNG is dropped before saving to database
-    {
-        let mut mcq_questions = vec![];
-        let mut short_questions = vec![];
-        let mut long_questions = vec![];
-
-        for q in &config::CONFIG.questions {
-            if q.pinned {
-                continue; // We add pinned questions later
-            } else {
-                match q.data {
-                    QuestionData::MultipleChoice { .. } => {
-                        mcq_questions.push(q);
-                    }
-                    QuestionData::Short { .. } => {
-                        short_questions.push(q);
-                    }
-                    QuestionData::Long { .. } => {
-                        long_questions.push(q);
-                    }
-                }
-            }
-        }
-
-        let mut rng = rand::thread_rng();
-
-        let mcq_choice: Vec<&Question> = mcq_questions
-            .choose_multiple(&mut rng, 4)
-            .cloned()
-            .collect();
-        let short_choice: Vec<&Question> = short_questions
-            .choose_multiple(&mut rng, 3)
-            .cloned()
-            .collect();
-        let long_choice: Vec<&Question> = long_questions
-            .choose_multiple(&mut rng, 2)
-            .cloned()
-            .collect();
-
-        for q in mcq_choice {
-            final_questions.push(q.clone()); // TODO: Try to remove clone
-        }
-
-        for q in short_choice {
-            final_questions.push(q.clone()); // TODO: Try to remove clone
-        }
-
-        for q in long_choice {
-            final_questions.push(q.clone()); // TODO: Try to remove clone
-        }
+    // Create questions randomly from the question bank
+    let bank = crate::questions::list(
+        &mut *app_state
+            .pool
+            .acquire()
+            .await
+            .map_err(|_| Error::Internal("Could not acquire database connection".to_string()))?,
+        false,
+    )
+    .await
+    .map_err(|e| Error::Internal(format!("Could not get questions: {}", e)))?;
 
-        // Add pinned questions
-        for q in &config::CONFIG.questions {
-            if q.pinned {
-                final_questions.push(q.clone()); // TODO: Try to remove clone
-            }
-        }
-    }
+    let final_questions = crate::quiz::build_quiz(&config::CONFIG.quiz, &bank)
+        .map_err(|e| Error::Internal(format!("Could not create quiz: {}", e)))?
+        .iter()
+        .map(BankQuestion::to_quiz_question)
+        .collect::<Vec<QuizQuestion>>();
 
     // Save questions to database
+    let cache_nonce = botox::crypto::gen_random(12);
+
     let quiz = json!({
         "questions": final_questions,
         "quiz_ver": 1,
-        "cache_nonce": botox::crypto::gen_random(12)
+        "cache_nonce": cache_nonce
     });
 
-    sqlx::query!(
-        "UPDATE staff_onboardings SET questions = $1 WHERE id = $2",
+    let started_at = sqlx::query!(
+        "UPDATE staff_onboardings SET questions = $1, quiz_started_at = NOW(), quiz_draft = NULL WHERE id = $2 RETURNING quiz_started_at",
         quiz,
         o_id
     )
-    .execute(&app_state.pool)
+    .fetch_one(&app_state.pool)
     .await
-    .map_err(|_| Error::new("Could not save questions".to_string()))?;
+    .map_err(|_| Error::Internal("Could not save questions".to_string()))?
+    .quiz_started_at
+    .map(|t| t.timestamp())
+    .unwrap_or_else(|| chrono::Utc::now().timestamp());
 
     // Convert final questions to PublicQuestion
 
@@ -613,18 +1125,140 @@ async fn create_quiz(
         questions: final_questions
             .iter()
             .map(|q| PublicQuestion {
-                question: q.question.clone(),
-                data: q.data.clone(),
-                pinned: q.pinned,
+                question: q.question.question.clone(),
+                data: q.question.data.clone(),
+                pinned: q.question.pinned,
+                section: q.question.section.clone(),
             })
             .collect::<Vec<PublicQuestion>>(),
         cached: false,
+        cache_nonce,
+        draft: None,
+        started_at,
+        expires_at: crate::quiz::quiz_deadline(&config::CONFIG.quiz, started_at),
     }))
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/SaveQuizDraft.ts")]
+struct SaveQuizDraft {
+    id: String,
+    /// ``cache_nonce`` of the quiz as returned by ``/quiz``
+    cache_nonce: String,
+    answers: HashMap<String, String>,
+    /// When each question was first seen and last edited, optional
+    #[serde(default)]
+    timings: HashMap<String, QuestionTiming>,
+}
+
+/// Saves the answers given so far, returning validation feedback for every question
+#[axum_macros::debug_handler]
+async fn save_quiz_draft(
+    State(app_state): State<Arc<AppState>>,
+    Auth(auth_data): Auth,
+    Payload(req): Payload<SaveQuizDraft>,
+) -> Result<Json<Vec<AnswerFeedback>>, Error> {
+
+    let o_id = uuid::Uuid::from_str(&req.id)
+        .map_err(|_| Error::InvalidRequest("Invalid id".to_string()))?;
+
+    let rec = sqlx::query!(
+        "SELECT state, questions, quiz_draft, quiz_started_at FROM staff_onboardings WHERE id = $1 AND user_id = $2 AND void = false",
+        o_id,
+        auth_data.user_id
+    )
+    .fetch_one(&app_state.pool)
+    .await
+    .map_err(|e| Error::query(e, "Could not find onboarding response"))?;
+
+    if rec.state != crate::states::OnboardState::InQuiz.to_string() {
+        return Err(Error::InvalidState(
+            "Paradise Protection Protocol is not enabled right now".to_string(),
+        ));
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let started_at = rec.quiz_started_at.map(|t| t.timestamp()).unwrap_or(now);
+
+    if crate::quiz::quiz_deadline(&config::CONFIG.quiz, started_at).is_some_and(|e| e < now) {
+        return Err(Error::QuizTimeExpired);
+    }
+
+    let quiz = rec.questions.unwrap_or(json!({}));
+
+    if quiz.get("cache_nonce").and_then(|n| n.as_str()) != Some(req.cache_nonce.as_str()) {
+        return Err(Error::QuizOutdated(
+            "This quiz is outdated, reload the page and try again".to_string(),
+        ));
+    }
+
+    let questions = serde_json::from_value::<Vec<QuizQuestion>>(
+        quiz.get("questions").cloned().unwrap_or(json!([])),
+    )
+    .map_err(|_| Error::Internal("Fatal error: Could not parse questions".to_string()))?;
+
+    // Only keep answers to questions that are actually in the quiz
+    let answers = req
+        .answers
+        .into_iter()
+        .filter(|(q, _)| questions.iter().any(|qq| &qq.question.question == q))
+        .collect::<HashMap<String, String>>();
+
+    let feedback = crate::quiz::check_answers(&questions, &answers);
+
+    let old_draft = rec
+        .quiz_draft
+        .and_then(|d| serde_json::from_value::<QuizDraft>(d).ok())
+        .filter(|d| d.cache_nonce == req.cache_nonce);
+
+    let (old_answers, mut timings) = old_draft
+        .map(|d| (d.answers, d.timings))
+        .unwrap_or_default();
+
+    for q in &questions {
+        let q = &q.question.question;
+
+        let mut new = req.timings.get(q).copied().unwrap_or_default();
+
+        // Fall back to the time of saving if the panel did not report an edit
+        if new.last_edited.is_none() && answers.get(q) != old_answers.get(q) {
+            new.last_edited = Some(now);
+        }
+
+        let timing = timings
+            .get(q)
+            .copied()
+            .unwrap_or_default()
+            .merge(new, started_at, now);
+
+        if timing.first_seen.is_some() || timing.last_edited.is_some() {
+            timings.insert(q.clone(), timing);
+        }
+    }
+
+    let draft = QuizDraft {
+        cache_nonce: req.cache_nonce,
+        answers,
+        timings,
+        updated_at: now,
+    };
+
+    sqlx::query!(
+        "UPDATE staff_onboardings SET quiz_draft = $1 WHERE id = $2",
+        serde_json::to_value(draft)
+            .map_err(|_| Error::Internal("Could not serialize draft".to_string()))?,
+        o_id
+    )
+    .execute(&app_state.pool)
+    .await
+    .map_err(|_| Error::Internal("Could not save draft".to_string()))?;
+
+    Ok(Json(feedback))
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/SubmitOnboarding.ts")]
 struct SubmitOnboarding {
-    login_token: String,
     id: String,
     quiz_answers: HashMap<String, String>,
     sv_code: String,
@@ -633,35 +1267,39 @@ struct SubmitOnboarding {
 #[axum_macros::debug_handler]
 async fn submit_onboarding(
     State(app_state): State<Arc<AppState>>,
-    Json(submit_onboarding_req): Json<SubmitOnboarding>,
+    Auth(auth_data): Auth,
+    Payload(submit_onboarding_req): Payload<SubmitOnboarding>,
 ) -> Result<impl IntoResponse, Error> {
-    let auth_data = super::auth::check_auth(
-        &app_state.pool,
-        &submit_onboarding_req.login_token,
-    )
-    .await
-    .map_err(Error::new)?;
 
     let o_id = uuid::Uuid::from_str(&submit_onboarding_req.id)
-        .map_err(|_| Error::new("Invalid id".to_string()))?;
+        .map_err(|_| Error::InvalidRequest("Invalid id".to_string()))?;
 
 
     let rec = sqlx::query!(
-        "SELECT state, guild_id, questions FROM staff_onboardings WHERE id = $1 AND user_id = $2 AND void = false",
+        "SELECT state, guild_id, questions, quiz_draft, quiz_started_at FROM staff_onboardings WHERE id = $1 AND user_id = $2 AND void = false",
         o_id,
         auth_data.user_id
     )
     .fetch_one(&app_state.pool)
     .await
-    .map_err(|_| Error::new("Could not find onboarding response".to_string()))?;
+    .map_err(|e| Error::query(e, "Could not find onboarding response"))?;
     
 
     if rec.state != crate::states::OnboardState::InQuiz.to_string() {
-        return Err(Error::new(
+        return Err(Error::InvalidState(
             "Paradise Protection Protocol is not enabled right now".to_string(),
         ));
     }
 
+    let now = chrono::Utc::now().timestamp();
+    let started_at = rec.quiz_started_at.map(|t| t.timestamp()).unwrap_or(now);
+
+    if crate::quiz::quiz_deadline(&config::CONFIG.quiz, started_at)
+        .is_some_and(|e| e + crate::quiz::QUIZ_SUBMIT_GRACE_SECS < now)
+    {
+        return Err(Error::QuizTimeExpired);
+    }
+
     // Check onboard_resp with corresponding resp id
     let questions = rec
     .questions
@@ -682,27 +1320,38 @@ async fn submit_onboarding(
         )
         .execute(&app_state.pool)
         .await
-        .map_err(|_| Error::new("Could not reset questions".to_string()))?;
+        .map_err(|_| Error::Internal("Could not reset questions".to_string()))?;
 
-        return Err(Error::new(
+        return Err(Error::QuizOutdated(
             "Quiz could not be found and hence has been reset, reload the page and try again"
                 .to_string(),
         ));
     }
 
-    if !crate::finish::check_code(
+    match crate::finish::check_code(
         &app_state.pool,
         o_id.hyphenated().to_string().as_str(),
         &auth_data.user_id,
         &submit_onboarding_req.sv_code,
     )
     .await
-    .map_err(|e| Error::new(e.to_string()))?
+    .map_err(|e| Error::Internal(format!("Could not check staff verification code: {}", e)))?
     {
-        // Incorrect code
-        return Err(Error::new("Incorrect staff verification code".to_string()));
+        CodeCheck::Correct => {}
+        CodeCheck::Incorrect => return Err(Error::IncorrectVerifyCode),
+        CodeCheck::Invalid => {
+            return Err(Error::InvalidRequest(
+                "Staff verification codes only contain letters and numbers".to_string(),
+            ))
+        }
+        CodeCheck::LockedOut(mins) => return Err(Error::VerifyCodeLocked(mins)),
     }
 
+    let questions_cache_nonce = questions
+        .get("cache_nonce")
+        .and_then(|n| n.as_str())
+        .map(|n| n.to_string());
+
     // Next parse the questions in DB
     let obj = json!([]);
     let quiz_qvals = questions.get("questions").unwrap_or(&obj).as_array();
@@ -711,73 +1360,424 @@ async fn submit_onboarding(
 
     if let Some(question_vals) = quiz_qvals {
         for q in question_vals {
-            // Parse question as Question
-            let question: Question = serde_json::from_value(q.clone()).map_err(|_| {
-                Error::new("Fatal error: Could not parse question".to_string())
+            // Parse question as QuizQuestion
+            let question: QuizQuestion = serde_json::from_value(q.clone()).map_err(|_| {
+                Error::Internal("Fatal error: Could not parse question".to_string())
             })?;
 
             questions.push(question);
         }
     }
 
-    // Now check that every answer is present, adding them to a vec in the order that they have been found
-    for question in &questions {
-        let answer = submit_onboarding_req
-            .quiz_answers
-            .get(&question.question)
-            .ok_or(Error::new(
-                "Missing answer for ".to_string() + &question.question,
-            ))?;
-
-        match question.data {
-            QuestionData::Short => {
-                if answer.len() < 50 {
-                    return Err(Error::new(
-                        "Short answer questions must be at least 50 characters long".to_string(),
-                    ));
-                }
-            }
-            QuestionData::Long => {
-                if answer.len() < 750 {
-                    return Err(Error::new(
-                        "Long answer questions must be at least 750 characters long".to_string(),
-                    ));
-                }
-            }
-            QuestionData::MultipleChoice(ref choices) => {
-                if !choices.contains(answer) {
-                    return Err(Error::new(
-                        "Invalid answer for multiple choice question".to_string(),
-                    ));
-                }
-            }
-        }
+    // Now check every answer, reporting all problems at once
+    let feedback = crate::quiz::check_answers(&questions, &submit_onboarding_req.quiz_answers);
+
+    if feedback.iter().any(|f| f.error.is_some()) {
+        return Err(Error::InvalidAnswers(feedback));
     }
 
+    let mut tx = app_state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| Error::Internal("Could not start transaction".to_string()))?;
+
+    crate::states::transition(
+        &mut *tx,
+        o_id,
+        crate::states::OnboardState::InQuiz,
+        crate::states::OnboardEvent::SubmitQuiz,
+        &crate::states::TransitionContext::new(&auth_data.user_id),
+    )
+    .await
+    .map_err(Error::transition)?;
+
+    let score = crate::quiz::grade_mcq(&questions, &submit_onboarding_req.quiz_answers);
+
+    let similarity = crate::similarity::check(
+        &mut *tx,
+        o_id,
+        &auth_data.user_id,
+        &questions,
+        &submit_onboarding_req.quiz_answers,
+    )
+    .await
+    .map_err(|e| Error::Internal(format!("Could not check answer similarity: {}", e)))?;
+
+    // Keep the timings reported while drafting the quiz
+    let mut question_timings = rec
+        .quiz_draft
+        .and_then(|d| serde_json::from_value::<QuizDraft>(d).ok())
+        .filter(|d| Some(&d.cache_nonce) == questions_cache_nonce.as_ref())
+        .map(|d| d.timings)
+        .unwrap_or_default();
+
+    question_timings.retain(|q, _| questions.iter().any(|qq| &qq.question.question == q));
+
+    let timings = QuizTimings {
+        started_at,
+        submitted_at: now,
+        time_limit: config::CONFIG.quiz.time_limit.map(|mins| (mins as i64) * 60),
+        questions: question_timings,
+    };
+
     sqlx::query!(
-        "UPDATE staff_onboardings SET questions = $1, answers = $2, state = $3 WHERE id = $4",
+        "UPDATE staff_onboardings SET questions = $1, answers = $2, score = $3, quiz_timings = $4, similarity = $5, quiz_draft = NULL WHERE id = $6",
         serde_json::to_value(questions).map_err(|_| {
-            Error::new("Fatal error: Could not serialize questions".to_string())
+            Error::Internal("Fatal error: Could not serialize questions".to_string())
         })?,
         serde_json::to_value(submit_onboarding_req.quiz_answers)
-            .map_err(|_| Error::new("Could not serialize answers".to_string()))?,
-        crate::states::OnboardState::PendingManagerReview.to_string(),
+            .map_err(|_| Error::Internal("Could not serialize answers".to_string()))?,
+        serde_json::to_value(&score)
+            .map_err(|_| Error::Internal("Could not serialize score".to_string()))?,
+        serde_json::to_value(&timings)
+            .map_err(|_| Error::Internal("Could not serialize timings".to_string()))?,
+        serde_json::to_value(&similarity)
+            .map_err(|_| Error::Internal("Could not serialize similarity".to_string()))?,
         o_id
     )
-    .execute(&app_state.pool)
+    .execute(&mut *tx)
     .await
-    .map_err(|_| Error::new("Could not save answers".to_string()))?;
+    .map_err(|_| Error::Internal("Could not save answers".to_string()))?;
+
+    tx.commit()
+        .await
+        .map_err(|_| Error::Internal("Could not commit transaction".to_string()))?;
+
+    let similarity_warning = match similarity.top_match() {
+        Some((question, m)) if similarity.flagged => format!(
+            "\n\n:warning: **Possible copied answers:** the answer to ``{}`` is {:.0}% similar to an answer by <@{}> (onboarding ``{}``)",
+            question,
+            m.similarity * 100.0,
+            m.user_id,
+            m.onboarding_id
+        ),
+        _ => String::new(),
+    };
+
+    crate::metrics::METRICS.quiz_submissions.inc();
 
     // Send message on discord
     crate::config::CONFIG.channels.onboarding_channel.say(
         &app_state.cache_http,
         format!(
-            "User <@{}> has submitted their onboarding quiz. Please see {}/onboarding/resp/{} to review it, then use the ``/admin approve/deny`` commands to approve or deny it.", 
+            "User <@{}> has submitted their onboarding quiz (automatically graded multiple choice score: **{}/{}**). Please see {}/onboarding/resp/{} to review it, then use the ``/admin approve/deny`` commands to approve or deny it.{}", 
             auth_data.user_id,
+            score.points,
+            score.max_points,
             crate::config::CONFIG.panel_url,
-            o_id
+            o_id,
+            similarity_warning
         )
-    ).await.map_err(|_| Error::new("Could not send message on discord".to_string()))?;
+    ).await.map_err(|_| Error::Internal("Could not send message on discord".to_string()))?;
 
     Ok((StatusCode::NO_CONTENT).into_response())
 }
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/ListQuestions.ts")]
+struct ListQuestions {
+    /// Also return questions that have been deleted
+    include_inactive: Option<bool>,
+}
+
+async fn list_questions(
+    State(app_state): State<Arc<AppState>>,
+    _: RequirePerm<ManageQuestions>,
+    Payload(req): Payload<ListQuestions>,
+) -> Result<Json<Vec<BankQuestion>>, Error> {
+
+    let questions = crate::questions::list(
+        &mut *app_state
+            .pool
+            .acquire()
+            .await
+            .map_err(|_| Error::Internal("Could not acquire database connection".to_string()))?,
+        req.include_inactive.unwrap_or(false),
+    )
+    .await
+    .map_err(|e| Error::Internal(format!("Could not get questions: {}", e)))?;
+
+    Ok(Json(questions))
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/GetQuestionRevisions.ts")]
+struct GetQuestionRevisions {
+    id: String,
+}
+
+async fn get_question_revisions(
+    State(app_state): State<Arc<AppState>>,
+    _: RequirePerm<ManageQuestions>,
+    Payload(req): Payload<GetQuestionRevisions>,
+) -> Result<Json<Vec<BankQuestion>>, Error> {
+
+    let id = uuid::Uuid::from_str(&req.id)
+        .map_err(|_| Error::InvalidRequest("Invalid id".to_string()))?;
+
+    let revisions = crate::questions::revisions(&app_state.pool, id)
+        .await
+        .map_err(|e| Error::Internal(format!("Could not get question revisions: {}", e)))?;
+
+    Ok(Json(revisions))
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/CreateQuestion.ts")]
+struct CreateQuestion {
+    question: Question,
+}
+
+async fn create_question(
+    State(app_state): State<Arc<AppState>>,
+    RequirePerm(auth_data, _): RequirePerm<ManageQuestions>,
+    Payload(req): Payload<CreateQuestion>,
+) -> Result<impl IntoResponse, Error> {
+
+    let id = crate::questions::create(&app_state.pool, &req.question, &auth_data.user_id)
+        .await
+        .map_err(Error::bank)?;
+
+    Ok(id.into_response())
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/UpdateQuestion.ts")]
+struct UpdateQuestion {
+    id: String,
+    question: Question,
+}
+
+async fn update_question(
+    State(app_state): State<Arc<AppState>>,
+    RequirePerm(auth_data, _): RequirePerm<ManageQuestions>,
+    Payload(req): Payload<UpdateQuestion>,
+) -> Result<Json<i32>, Error> {
+
+    let id = uuid::Uuid::from_str(&req.id)
+        .map_err(|_| Error::InvalidRequest("Invalid id".to_string()))?;
+
+    let revision = crate::questions::update(&app_state.pool, id, &req.question, &auth_data.user_id)
+        .await
+        .map_err(Error::bank)?;
+
+    Ok(Json(revision))
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/DeleteQuestion.ts")]
+struct DeleteQuestion {
+    id: String,
+}
+
+async fn delete_question(
+    State(app_state): State<Arc<AppState>>,
+    _: RequirePerm<ManageQuestions>,
+    Payload(req): Payload<DeleteQuestion>,
+) -> Result<impl IntoResponse, Error> {
+
+    let id = uuid::Uuid::from_str(&req.id)
+        .map_err(|_| Error::InvalidRequest("Invalid id".to_string()))?;
+
+    crate::questions::deactivate(&app_state.pool, id)
+        .await
+        .map_err(Error::bank)?;
+
+    Ok((StatusCode::NO_CONTENT).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{
+            header::{AUTHORIZATION, CONTENT_TYPE, LOCATION},
+            Request,
+        },
+        response::Response,
+    };
+    use hyper::service::Service;
+    use serde::de::DeserializeOwned;
+
+    use super::*;
+
+    const USER_ID: &str = "563808552288780322";
+
+    async fn send(app: &Router, req: Request<Body>) -> Response {
+        app.clone().call(req).await.unwrap()
+    }
+
+    async fn body<T: DeserializeOwned>(res: Response) -> T {
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    fn cache_http() -> CacheHttpImpl {
+        CacheHttpImpl {
+            cache: Arc::new(serenity::all::Cache::new()),
+            http: Arc::new(serenity::all::Http::new("")),
+        }
+    }
+
+    fn location(res: &Response) -> reqwest::Url {
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+        reqwest::Url::parse(res.headers()[LOCATION].to_str().unwrap()).unwrap()
+    }
+
+    /// The app is called directly, so only the path and query of a URL are requested
+    fn get(url: &reqwest::Url) -> Request<Body> {
+        Request::get(format!("{}?{}", url.path(), url.query().unwrap_or_default()))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[sqlx::test(migrations = false, fixtures("base_schema"))]
+    async fn mock_login_creates_session(pool: PgPool) {
+        sqlx::migrate!().run(&pool).await.unwrap();
+
+        sqlx::query("INSERT INTO staff_members (user_id, positions) VALUES ($1, $2)")
+            .bind(USER_ID)
+            .bind(vec![uuid::Uuid::new_v4()])
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let app = app(pool, cache_http());
+
+        let redirect = format!("{}/login", config::CONFIG.panel_url);
+        let state = ConfirmLoginState::CreateSession(redirect.clone(), None);
+
+        let res = send(
+            &app,
+            Request::get(format!("/create-login?state={}", state))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+
+        let cookie = res.headers()[SET_COOKIE]
+            .to_str()
+            .unwrap()
+            .split(';')
+            .next()
+            .unwrap()
+            .to_string();
+
+        let mut authorize = location(&res);
+        assert_eq!(authorize.path(), "/mock-oauth2/authorize");
+
+        authorize.query_pairs_mut().append_pair("user_id", USER_ID);
+
+        let confirm = location(&send(&app, get(&authorize)).await);
+        assert_eq!(confirm.path(), "/confirm-login");
+
+        let mut req = get(&confirm);
+        req.headers_mut().insert(COOKIE, cookie.parse().unwrap());
+
+        let back = location(&send(&app, req).await);
+        assert!(back.as_str().starts_with(&redirect));
+
+        let (_, code) = back.query_pairs().find(|(k, _)| k == "code").unwrap();
+
+        let res = send(
+            &app,
+            Request::post("/exchange-code")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(json!({ "code": code }).to_string()))
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let tokens: SessionTokens = body(res).await;
+
+        let res = send(
+            &app,
+            Request::get("/auth-data")
+                .header(AUTHORIZATION, format!("Bearer {}", tokens.token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let auth_data: AuthData = body(res).await;
+        assert_eq!(auth_data.user_id, USER_ID);
+    }
+
+    /// Collects every ``$ref`` in a schema
+    fn refs<'a>(value: &'a serde_json::Value, out: &mut Vec<&'a str>) {
+        match value {
+            serde_json::Value::Object(map) => {
+                for (key, value) in map {
+                    match value.as_str() {
+                        Some(r) if key == "$ref" => out.push(r),
+                        _ => refs(value, out),
+                    }
+                }
+            }
+            serde_json::Value::Array(values) => values.iter().for_each(|v| refs(v, out)),
+            _ => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn openapi_documents_every_route() {
+        // The database is never queried, documents are built without it
+        let pool = PgPool::connect_lazy("postgres://localhost/persepolis").unwrap();
+        let shared_state = Arc::new(AppState::new(pool, cache_http()));
+
+        let routes = routes(&shared_state);
+        let operations = routes.operations();
+
+        let app = routes.finish().with_state(shared_state);
+
+        let res = send(&app, Request::get("/openapi.json").body(Body::empty()).unwrap()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let doc: serde_json::Value = body(res).await;
+
+        assert_eq!(doc["openapi"], "3.0.3");
+
+        for (path, method) in operations
+            .iter()
+            .map(|(path, method)| (*path, method.as_str().to_lowercase()))
+            .chain([("/openapi.json", "get".to_string())])
+        {
+            assert!(
+                doc["paths"][path][&method].is_object(),
+                "{} {} is not documented",
+                method,
+                path
+            );
+        }
+
+        // Config::test uses the mock provider, its routes are part of the table
+        assert!(operations.iter().any(|(path, _)| *path == "/mock-oauth2/authorize"));
+
+        let schemas = doc["components"]["schemas"].as_object().unwrap();
+
+        let mut all_refs = vec![];
+        refs(&doc, &mut all_refs);
+
+        for r in all_refs {
+            let name = r.strip_prefix("#/components/schemas/").unwrap_or_else(|| panic!("{} is not a component", r));
+            assert!(schemas.contains_key(name), "{} is not defined", r);
+        }
+
+        for (name, schema) in schemas {
+            assert!(schema.is_object(), "{} is not a schema object", name);
+            assert!(
+                schema.get("type").is_some() || schema.get("oneOf").is_some() || schema.get("enum").is_some(),
+                "{} has no type",
+                name
+            );
+        }
+
+        let session = &schemas["SessionTokens"];
+        assert_eq!(session["type"], "object");
+        assert_eq!(session["properties"]["token"]["type"], "string");
+        assert!(session["required"].as_array().unwrap().contains(&json!("token")));
+    }
+}
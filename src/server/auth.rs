@@ -1,40 +1,307 @@
-use crate::Error;
-use sqlx::PgPool;
+use std::{collections::HashMap, sync::Mutex};
 
-use super::types::auth::AuthData;
+use log::info;
+use sqlx::{
+    types::{chrono, uuid},
+    PgPool,
+};
 
-/// Checks auth, but does not ensure active sessions
-pub async fn check_auth(pool: &PgPool, token: &str) -> Result<AuthData, Error> {
-    // Delete expired auths
-    sqlx::query!("DELETE FROM staffpanel__authchain WHERE state = $1 AND created_at < NOW() - INTERVAL '1 hour'", "persepolis.active")
-        .execute(pool)
-        .await?;
+use super::error::Error;
+use crate::config::CONFIG;
 
-    let count = sqlx::query!(
-        "SELECT COUNT(*) FROM staffpanel__authchain WHERE token = $1 AND state = $2",
-        token,
-        "persepolis.active"
-    )
-    .fetch_one(pool)
-    .await?
-    .count
-    .unwrap_or(0);
+use super::types::auth::{AuthData, SessionInfo, SessionTokens};
+
+/// State of sessions created by persepolis in ``staffpanel__authchain``
+const SESSION_STATE: &str = "persepolis.active";
+
+/// How long a session token is valid for before it must be refreshed
+pub const SESSION_EXPIRY_MINS: i32 = 60;
+
+/// How long a refresh token is valid for, refreshing does not extend this
+pub const REFRESH_EXPIRY_DAYS: i32 = 30;
+
+/// Hashes a session or refresh token with ``session_secret``, only the hash is ever stored
+fn hash_token(token: &str) -> String {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, CONFIG.session_secret.as_bytes());
+
+    data_encoding::HEXLOWER.encode(ring::hmac::sign(&key, token.as_bytes()).as_ref())
+}
+
+/// How long a login code can be exchanged for after the login
+pub const LOGIN_CODE_TTL_SECS: i64 = 60;
+
+struct PendingLogin {
+    user_id: String,
+    code_challenge: Option<String>,
+    expires_at: i64,
+}
+
+/// Short-lived, single use codes logins redirect back with, exchanged for session tokens
+///
+/// Sessions are only created once a code is exchanged
+#[derive(Default)]
+pub struct LoginCodes {
+    /// Keyed by the hash of the code
+    pending: Mutex<HashMap<String, PendingLogin>>,
+}
+
+impl LoginCodes {
+    /// Issues a code for a user, ``code_challenge`` is the PKCE challenge the verifier must match
+    pub fn issue(&self, user_id: &str, code_challenge: Option<String>) -> String {
+        let code = botox::crypto::gen_random(64);
+        let now = chrono::Utc::now().timestamp();
+
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+
+        pending.retain(|_, p| p.expires_at >= now);
+
+        pending.insert(
+            hash_token(&code),
+            PendingLogin {
+                user_id: user_id.to_string(),
+                code_challenge,
+                expires_at: now + LOGIN_CODE_TTL_SECS,
+            },
+        );
 
-    if count == 0 {
-        return Err("identityExpired".into());
+        code
     }
 
+    /// Redeems a code, returning the user it was issued for
+    ///
+    /// The code is used up even if the verifier is wrong so it cannot be brute forced
+    pub fn redeem(&self, code: &str, code_verifier: Option<&str>) -> Result<String, Error> {
+        let login = self
+            .pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&hash_token(code))
+            .filter(|p| p.expires_at >= chrono::Utc::now().timestamp())
+            .ok_or_else(|| Error::LoginFailed("Invalid or expired login code".to_string()))?;
+
+        if let Some(code_challenge) = login.code_challenge {
+            let Some(code_verifier) = code_verifier else {
+                return Err(Error::LoginFailed("Missing code verifier".to_string()));
+            };
+
+            let hash = ring::digest::digest(&ring::digest::SHA256, code_verifier.as_bytes());
+            let expected = data_encoding::BASE64URL_NOPAD.encode(hash.as_ref());
+
+            if ring::constant_time::verify_slices_are_equal(
+                expected.as_bytes(),
+                code_challenge.as_bytes(),
+            )
+            .is_err()
+            {
+                return Err(Error::LoginFailed("Incorrect code verifier".to_string()));
+            }
+        }
+
+        Ok(login.user_id)
+    }
+}
+
+/// Checks auth, but does not ensure active sessions
+pub async fn check_auth(pool: &PgPool, token: &str) -> Result<AuthData, Error> {
+    // Unknown, expired and revoked sessions all take the same path: the token is always hashed
+    // and looked up once, and every miss returns the same error
+    //
+    // Sessions created before expiry tracking expire an hour after creation
     let rec = sqlx::query!(
-        "SELECT user_id, created_at, state FROM staffpanel__authchain WHERE token = $1 AND state = $2",
-        token,
-        "persepolis.active"
+        "
+SELECT user_id, created_at, state, session_id, COALESCE(expires_at, created_at + INTERVAL '1 hour') AS expires_at
+FROM staffpanel__authchain
+WHERE token_hash = $1 AND state = $2 AND COALESCE(expires_at, created_at + INTERVAL '1 hour') > NOW()
+        ",
+        hash_token(token),
+        SESSION_STATE
     )
-    .fetch_one(pool)
-    .await?;
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| Error::Internal(format!("Could not check session: {}", e)))?
+    .ok_or(Error::IdentityExpired)?;
 
     Ok(AuthData {
         user_id: rec.user_id,
         created_at: rec.created_at.timestamp(),
         state: rec.state,
+        session_id: rec.session_id.hyphenated().to_string(),
+        expires_at: rec.expires_at.map(|t| t.timestamp()).unwrap_or_default(),
+    })
+}
+
+/// Creates a new session for a user
+pub async fn create_session(pool: &PgPool, user_id: &str) -> Result<SessionTokens, crate::Error> {
+    let token = botox::crypto::gen_random(512);
+    let refresh_token = botox::crypto::gen_random(128);
+
+    let rec = sqlx::query!(
+        "
+INSERT INTO staffpanel__authchain (user_id, token, token_hash, popplio_token, state, expires_at, refresh_token_hash, refresh_expires_at)
+VALUES ($1, 'hashed:' || gen_random_uuid(), $2, $3, $4, NOW() + make_interval(mins => $5), $6, NOW() + make_interval(days => $7))
+RETURNING expires_at, refresh_expires_at
+        ",
+        user_id,
+        hash_token(&token),
+        botox::crypto::gen_random(2048),
+        SESSION_STATE,
+        SESSION_EXPIRY_MINS,
+        hash_token(&refresh_token),
+        REFRESH_EXPIRY_DAYS
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(SessionTokens {
+        token,
+        refresh_token,
+        expires_at: rec.expires_at.map(|t| t.timestamp()).unwrap_or_default(),
+        refresh_expires_at: rec
+            .refresh_expires_at
+            .map(|t| t.timestamp())
+            .unwrap_or_default(),
     })
 }
+
+/// Issues new tokens for a session, returning ``None`` if the refresh token is invalid or expired
+///
+/// Both tokens are rotated so a refresh token can only be used once
+pub async fn refresh_session(
+    pool: &PgPool,
+    refresh_token: &str,
+) -> Result<Option<SessionTokens>, crate::Error> {
+    let token = botox::crypto::gen_random(512);
+    let new_refresh_token = botox::crypto::gen_random(128);
+
+    let rec = sqlx::query!(
+        "
+UPDATE staffpanel__authchain
+SET token_hash = $1, refresh_token_hash = $2, expires_at = NOW() + make_interval(mins => $3)
+WHERE refresh_token_hash = $4 AND state = $5 AND refresh_expires_at > NOW()
+RETURNING expires_at, refresh_expires_at
+        ",
+        hash_token(&token),
+        hash_token(&new_refresh_token),
+        SESSION_EXPIRY_MINS,
+        hash_token(refresh_token),
+        SESSION_STATE
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(rec.map(|rec| SessionTokens {
+        token,
+        refresh_token: new_refresh_token,
+        expires_at: rec.expires_at.map(|t| t.timestamp()).unwrap_or_default(),
+        refresh_expires_at: rec
+            .refresh_expires_at
+            .map(|t| t.timestamp())
+            .unwrap_or_default(),
+    }))
+}
+
+/// Returns the sessions of a user that can still be used or refreshed, newest first
+pub async fn list_sessions(
+    pool: &PgPool,
+    user_id: &str,
+    current_session: &str,
+) -> Result<Vec<SessionInfo>, crate::Error> {
+    let rows = sqlx::query!(
+        "
+SELECT session_id, created_at, COALESCE(expires_at, created_at + INTERVAL '1 hour') AS expires_at, refresh_expires_at
+FROM staffpanel__authchain
+WHERE user_id = $1 AND state = $2
+AND (COALESCE(expires_at, created_at + INTERVAL '1 hour') > NOW() OR refresh_expires_at > NOW())
+ORDER BY created_at DESC
+        ",
+        user_id,
+        SESSION_STATE
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| {
+            let session_id = r.session_id.hyphenated().to_string();
+
+            SessionInfo {
+                current: session_id == current_session,
+                session_id,
+                created_at: r.created_at.timestamp(),
+                expires_at: r.expires_at.map(|t| t.timestamp()).unwrap_or_default(),
+                refresh_expires_at: r.refresh_expires_at.map(|t| t.timestamp()),
+            }
+        })
+        .collect())
+}
+
+/// Revokes a single session
+pub async fn revoke_session(pool: &PgPool, session_id: uuid::Uuid) -> Result<(), crate::Error> {
+    sqlx::query!(
+        "DELETE FROM staffpanel__authchain WHERE session_id = $1 AND state = $2",
+        session_id,
+        SESSION_STATE
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Revokes every session of a user, returning the number of revoked sessions
+pub async fn revoke_user_sessions(pool: &PgPool, user_id: &str) -> Result<u64, crate::Error> {
+    let res = sqlx::query!(
+        "DELETE FROM staffpanel__authchain WHERE user_id = $1 AND state = $2",
+        user_id,
+        SESSION_STATE
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(res.rows_affected())
+}
+
+/// Deletes sessions that can no longer be used or refreshed, returning the number of deleted sessions
+pub async fn prune_expired_sessions(pool: &PgPool) -> Result<u64, crate::Error> {
+    let res = sqlx::query!(
+        "DELETE FROM staffpanel__authchain WHERE state = $1 AND COALESCE(refresh_expires_at, expires_at, created_at + INTERVAL '1 hour') < NOW()",
+        SESSION_STATE
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(res.rows_affected())
+}
+
+/// Replaces the plaintext tokens of sessions created before token hashing with their hashes,
+/// returning the number of migrated sessions
+pub async fn hash_legacy_sessions(pool: &PgPool) -> Result<u64, crate::Error> {
+    let rows = sqlx::query!(
+        "SELECT session_id, token, refresh_token FROM staffpanel__authchain WHERE state = $1 AND token_hash IS NULL",
+        SESSION_STATE
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut tx = pool.begin().await?;
+
+    for row in &rows {
+        sqlx::query!(
+            "UPDATE staffpanel__authchain SET token = 'hashed:' || session_id, token_hash = $1, refresh_token = NULL, refresh_token_hash = $2 WHERE session_id = $3",
+            hash_token(&row.token),
+            row.refresh_token.as_deref().map(hash_token),
+            row.session_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    if !rows.is_empty() {
+        info!("Hashed the tokens of {} existing sessions", rows.len());
+    }
+
+    Ok(rows.len() as u64)
+}
@@ -0,0 +1,203 @@
+use axum::{
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use log::error;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::questions::BankError;
+use crate::quiz::AnswerFeedback;
+use crate::states::TransitionError;
+
+/// Stable, machine readable error codes returned by the API
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, JsonSchema, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export, export_to = ".generated/ApiErrorCode.ts")]
+pub enum ApiErrorCode {
+    IdentityExpired,
+    LoginFailed,
+    Forbidden,
+    MissingPermission,
+    NotFound,
+    InvalidState,
+    QuizOutdated,
+    QuizTimeExpired,
+    VerifyCodeLocked,
+    InvalidRequest,
+    InvalidAnswers,
+    IncorrectVerifyCode,
+    RateLimited,
+    Internal,
+}
+
+impl ApiErrorCode {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::IdentityExpired | Self::LoginFailed => StatusCode::UNAUTHORIZED,
+            Self::Forbidden | Self::MissingPermission => StatusCode::FORBIDDEN,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::InvalidState | Self::QuizOutdated | Self::QuizTimeExpired => StatusCode::CONFLICT,
+            Self::VerifyCodeLocked | Self::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            Self::InvalidRequest | Self::InvalidAnswers | Self::IncorrectVerifyCode => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            Self::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Body of every error response
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[schemars(rename = "ApiError")]
+#[ts(export, export_to = ".generated/ApiError.ts")]
+pub struct ApiErrorResponse {
+    pub code: ApiErrorCode,
+    /// Human readable description of the error
+    pub message: String,
+    /// Per-question feedback, only set for ``invalidAnswers``
+    pub answers: Option<Vec<AnswerFeedback>>,
+}
+
+pub enum Error {
+    /// The login token is invalid or has expired
+    IdentityExpired,
+    /// Logging in through Discord failed
+    LoginFailed(String),
+    /// The user is not allowed to do this
+    Forbidden(String),
+    /// The user is missing the given ``persepolis`` permission
+    MissingPermission(String),
+    NotFound(String),
+    /// The onboarding is not in a state that allows this
+    InvalidState(String),
+    /// The quiz changed since it was loaded
+    QuizOutdated(String),
+    /// The quiz time limit has run out
+    QuizTimeExpired,
+    /// Too many incorrect staff verification codes were inputted, contains the minutes left
+    VerifyCodeLocked(i64),
+    /// The request is malformed or failed validation
+    InvalidRequest(String),
+    /// One or more quiz answers are invalid
+    InvalidAnswers(Vec<AnswerFeedback>),
+    IncorrectVerifyCode,
+    /// Too many requests were made, contains the seconds to wait before retrying
+    RateLimited(u64),
+    /// Something went wrong on our side. The details are logged but never sent to the client
+    /// as they may contain database errors
+    Internal(String),
+}
+
+impl Error {
+    /// Maps a failed query to [`Error::NotFound`] if no row was found and [`Error::Internal`] otherwise
+    ///
+    /// ``not_found`` is sent to the client, the query error is only logged
+    pub fn query(e: sqlx::Error, not_found: &str) -> Self {
+        match e {
+            sqlx::Error::RowNotFound => Self::NotFound(not_found.to_string()),
+            e => Self::Internal(format!("{}: {}", not_found, e)),
+        }
+    }
+
+    /// Maps a failed state transition to [`Error::InvalidState`] if the transition was rejected
+    pub fn transition(e: crate::Error) -> Self {
+        match e.downcast_ref::<TransitionError>() {
+            Some(e) => Self::InvalidState(e.to_string()),
+            None => Self::Internal(e.to_string()),
+        }
+    }
+
+    /// Maps a rejected question bank change to [`Error::NotFound`] or [`Error::InvalidRequest`]
+    pub fn bank(e: crate::Error) -> Self {
+        match e.downcast_ref::<BankError>() {
+            Some(BankError::NotFound) => Self::NotFound(e.to_string()),
+            Some(BankError::BreaksBlueprint(_)) => Self::InvalidRequest(e.to_string()),
+            None => Self::Internal(e.to_string()),
+        }
+    }
+
+    pub fn code(&self) -> ApiErrorCode {
+        match self {
+            Self::IdentityExpired => ApiErrorCode::IdentityExpired,
+            Self::LoginFailed(_) => ApiErrorCode::LoginFailed,
+            Self::Forbidden(_) => ApiErrorCode::Forbidden,
+            Self::MissingPermission(_) => ApiErrorCode::MissingPermission,
+            Self::NotFound(_) => ApiErrorCode::NotFound,
+            Self::InvalidState(_) => ApiErrorCode::InvalidState,
+            Self::QuizOutdated(_) => ApiErrorCode::QuizOutdated,
+            Self::QuizTimeExpired => ApiErrorCode::QuizTimeExpired,
+            Self::VerifyCodeLocked(_) => ApiErrorCode::VerifyCodeLocked,
+            Self::InvalidRequest(_) => ApiErrorCode::InvalidRequest,
+            Self::InvalidAnswers(_) => ApiErrorCode::InvalidAnswers,
+            Self::IncorrectVerifyCode => ApiErrorCode::IncorrectVerifyCode,
+            Self::RateLimited(_) => ApiErrorCode::RateLimited,
+            Self::Internal(_) => ApiErrorCode::Internal,
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.code().status()
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            Self::IdentityExpired => "Your session has expired, please login again".to_string(),
+            Self::MissingPermission(perm) => {
+                format!("You need the `persepolis.{}` permission to do this", perm)
+            }
+            Self::QuizTimeExpired => {
+                "The time limit for this quiz has run out! Contact management for more information"
+                    .to_string()
+            }
+            Self::VerifyCodeLocked(mins) => format!(
+                "Too many incorrect staff verification codes, try again in {} minutes",
+                mins
+            ),
+            Self::InvalidAnswers(feedback) => feedback
+                .iter()
+                .filter_map(|f| f.error.as_ref().map(|e| format!("{}: {}", f.question, e)))
+                .collect::<Vec<String>>()
+                .join("\n"),
+            Self::IncorrectVerifyCode => "Incorrect staff verification code".to_string(),
+            Self::RateLimited(secs) => format!(
+                "You are being ratelimited, try again in {} seconds",
+                secs
+            ),
+            Self::LoginFailed(msg)
+            | Self::Forbidden(msg)
+            | Self::NotFound(msg)
+            | Self::InvalidState(msg)
+            | Self::QuizOutdated(msg)
+            | Self::InvalidRequest(msg) => msg.clone(),
+            Self::Internal(_) => "Internal server error".to_string(),
+        }
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        if let Self::Internal(msg) = &self {
+            error!("Internal error in RPC server: {}", msg);
+        }
+
+        let body = ApiErrorResponse {
+            code: self.code(),
+            message: self.message(),
+            answers: match &self {
+                Self::InvalidAnswers(feedback) => Some(feedback.clone()),
+                _ => None,
+            },
+        };
+
+        let mut response = (self.status(), Json(body)).into_response();
+
+        if let Self::RateLimited(secs) = &self {
+            response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(*secs));
+        }
+
+        response
+    }
+}
@@ -0,0 +1,192 @@
+use std::{marker::PhantomData, sync::Arc};
+
+use axum::{
+    async_trait,
+    body::{Body, Bytes},
+    extract::{FromRequest, FromRequestParts, Query, State},
+    http::{header::AUTHORIZATION, request::Parts, Method, Request},
+    middleware::Next,
+    response::Response,
+    Json,
+};
+use serde::de::DeserializeOwned;
+
+use super::{api::AppState, error::Error, types::auth::AuthData};
+
+/// Reads the login token of a request
+///
+/// The ``Authorization`` header is preferred, ``login_token`` in the JSON body is still accepted
+/// while the panel migrates to the header
+async fn login_token(req: Request<Body>) -> Result<(Request<Body>, Option<String>), Error> {
+    if let Some(header) = req.headers().get(AUTHORIZATION) {
+        let header = header
+            .to_str()
+            .map_err(|_| Error::InvalidRequest("Invalid Authorization header".to_string()))?;
+
+        let token = header.strip_prefix("Bearer ").unwrap_or(header).to_string();
+
+        return Ok((req, Some(token)));
+    }
+
+    if req.method() == Method::GET {
+        return Ok((req, None));
+    }
+
+    let (parts, body) = req.into_parts();
+
+    let bytes = Bytes::from_request(Request::new(body), &())
+        .await
+        .map_err(|e| Error::InvalidRequest(format!("Could not read body: {}", e)))?;
+
+    let token = serde_json::from_slice::<serde_json::Value>(&bytes)
+        .ok()
+        .and_then(|v| v.get("login_token")?.as_str().map(|t| t.to_string()));
+
+    Ok((Request::from_parts(parts, Body::from(bytes)), token))
+}
+
+/// Middleware for routes that need a login, resolves the [`AuthData`] of the request once
+pub async fn require_auth(
+    State(app_state): State<Arc<AppState>>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, Error> {
+    let (mut req, token) = login_token(req).await?;
+
+    let auth_data =
+        super::auth::check_auth(&app_state.pool, &token.ok_or(Error::IdentityExpired)?).await?;
+
+    req.extensions_mut().insert(auth_data);
+
+    Ok(next.run(req).await)
+}
+
+/// The logged in user, requires the route to be behind [`require_auth`]
+pub struct Auth(pub AuthData);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Auth {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<AuthData>()
+            .cloned()
+            .map(Auth)
+            .ok_or_else(|| Error::Internal("Route is missing the auth middleware".to_string()))
+    }
+}
+
+/// Resolved kittycat permissions of the logged in user
+#[derive(Clone)]
+pub struct StaffPerms {
+    pub auth: AuthData,
+    pub perms: Vec<String>,
+}
+
+impl StaffPerms {
+    /// Returns whether the user has the given ``persepolis`` permission
+    pub fn has(&self, perm: &str) -> bool {
+        kittycat::perms::has_perm(&self.perms, &kittycat::perms::build("persepolis", perm))
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for StaffPerms {
+    type Rejection = Error;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        if let Some(perms) = parts.extensions.get::<StaffPerms>() {
+            return Ok(perms.clone());
+        }
+
+        let Auth(auth) = Auth::from_request_parts(parts, state).await?;
+
+        let perms = crate::perms::get_user_perms(&state.pool, &auth.user_id)
+            .await
+            .map_err(|e| Error::Internal(format!("Could not get user perms: {}", e)))?
+            .resolve();
+
+        let perms = StaffPerms { auth, perms };
+
+        parts.extensions.insert(perms.clone());
+
+        Ok(perms)
+    }
+}
+
+/// A ``persepolis`` permission a handler can require through [`RequirePerm`]
+pub trait Permission: Send + Sync {
+    const NAME: &'static str;
+}
+
+macro_rules! permissions {
+    ($($name:ident => $perm:literal),* $(,)?) => {
+        $(
+            pub struct $name;
+
+            impl Permission for $name {
+                const NAME: &'static str = $perm;
+            }
+        )*
+    };
+}
+
+permissions! {
+    ViewOnboardingResponses => "view_onboarding_responses",
+    GradeOnboarding => "grade_onboarding",
+    ManageQuestions => "manage_questions",
+    RevokeSessions => "revoke_sessions",
+    ViewReports => "view_reports",
+}
+
+/// The logged in user, rejecting the request if they do not have the permission ``P``
+pub struct RequirePerm<P: Permission>(pub AuthData, pub PhantomData<P>);
+
+#[async_trait]
+impl<P: Permission> FromRequestParts<Arc<AppState>> for RequirePerm<P> {
+    type Rejection = Error;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let perms = StaffPerms::from_request_parts(parts, state).await?;
+
+        if !perms.has(P::NAME) {
+            return Err(Error::MissingPermission(P::NAME.to_string()));
+        }
+
+        Ok(RequirePerm(perms.auth, PhantomData))
+    }
+}
+
+/// Request data, read from the query string for ``GET`` requests and from the JSON body otherwise
+pub struct Payload<T>(pub T);
+
+#[async_trait]
+impl<T: DeserializeOwned + Send, S: Send + Sync> FromRequest<S, Body> for Payload<T> {
+    type Rejection = Error;
+
+    async fn from_request(req: Request<Body>, state: &S) -> Result<Self, Self::Rejection> {
+        if req.method() == Method::GET {
+            let (mut parts, _) = req.into_parts();
+
+            let Query(data) = Query::<T>::from_request_parts(&mut parts, state)
+                .await
+                .map_err(|e| Error::InvalidRequest(e.to_string()))?;
+
+            Ok(Payload(data))
+        } else {
+            let Json(data) = Json::<T>::from_request(req, state)
+                .await
+                .map_err(|e| Error::InvalidRequest(e.to_string()))?;
+
+            Ok(Payload(data))
+        }
+    }
+}
@@ -0,0 +1,44 @@
+-- Tables persepolis shares with the rest of Infinity Bot List, only the columns persepolis uses.
+--
+-- These come from the IBL seed database in production, tests create them from this file and
+-- then apply ``migrations`` on top like ``sqlx migrate run`` would
+CREATE TABLE IF NOT EXISTS staff_positions (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    index INTEGER NOT NULL,
+    perms TEXT[] NOT NULL DEFAULT '{}'
+);
+
+CREATE TABLE IF NOT EXISTS staff_members (
+    user_id TEXT PRIMARY KEY,
+    positions UUID[] NOT NULL DEFAULT '{}',
+    perm_overrides TEXT[] NOT NULL DEFAULT '{}'
+);
+
+CREATE TABLE IF NOT EXISTS bots (
+    bot_id TEXT PRIMARY KEY,
+    short TEXT NOT NULL,
+    invite TEXT NOT NULL,
+    type TEXT NOT NULL DEFAULT 'approved'
+);
+
+CREATE TABLE IF NOT EXISTS staffpanel__authchain (
+    user_id TEXT NOT NULL,
+    token TEXT NOT NULL UNIQUE,
+    popplio_token TEXT NOT NULL,
+    state TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS staff_onboardings (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id TEXT NOT NULL,
+    guild_id TEXT NOT NULL,
+    state TEXT NOT NULL DEFAULT 'pending',
+    verdict JSONB,
+    questions JSONB,
+    answers JSONB,
+    staff_verify_code TEXT,
+    void BOOLEAN NOT NULL DEFAULT false,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    finished_at TIMESTAMPTZ
+);
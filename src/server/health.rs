@@ -0,0 +1,154 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::{MatchedPath, State},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        HeaderMap, Request, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use ts_rs::TS;
+
+use super::{api::AppState, error::Error};
+use crate::{config::CONFIG, metrics::METRICS};
+
+/// How long the database may take to answer a readiness check
+const DATABASE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long onboarding counts are reused for, so scrapes do not each query the database
+const ONBOARDING_COUNTS_TTL: Duration = Duration::from_secs(15);
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/Readiness.ts")]
+pub struct Readiness {
+    /// A connection could be acquired and queried
+    pub database: bool,
+    /// Every shard is connected to the gateway
+    pub gateway: bool,
+    /// The cache has received guilds
+    pub cache: bool,
+}
+
+/// Always succeeds while the RPC server is running
+pub async fn healthz() -> impl IntoResponse {
+    "ok"
+}
+
+/// Returns ``503`` until persepolis can serve requests
+pub async fn readyz(State(app_state): State<Arc<AppState>>) -> impl IntoResponse {
+    let database = tokio::time::timeout(
+        DATABASE_TIMEOUT,
+        sqlx::query("SELECT 1").execute(&app_state.pool),
+    )
+    .await
+    .is_ok_and(|res| res.is_ok());
+
+    let (gateway, _) = crate::metrics::gateway_status().await;
+
+    let cache = app_state.cache_http.cache.guild_count() > 0;
+
+    let status = if database && gateway && cache {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(Readiness {
+            database,
+            gateway,
+            cache,
+        }),
+    )
+}
+
+/// Onboarding counts last served at ``/metrics``, along with when they were counted
+#[derive(Default)]
+pub struct OnboardingCounts(tokio::sync::Mutex<Option<(Instant, Vec<(String, i64)>)>>);
+
+impl OnboardingCounts {
+    async fn get(&self, pool: &PgPool) -> Result<Vec<(String, i64)>, crate::Error> {
+        // Held while counting so concurrent scrapes wait for one query instead of each running it
+        let mut cached = self.0.lock().await;
+
+        if let Some((counted_at, counts)) = cached.as_ref() {
+            if counted_at.elapsed() < ONBOARDING_COUNTS_TTL {
+                return Ok(counts.clone());
+            }
+        }
+
+        let counts = crate::history::count_by_state(pool).await?;
+
+        *cached = Some((Instant::now(), counts.clone()));
+
+        Ok(counts)
+    }
+}
+
+/// Requires ``rpc_server.metrics_token`` as a bearer token, metrics are not served if it is unset
+pub async fn metrics(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, Error> {
+    let Some(metrics_token) = &CONFIG.rpc_server.metrics_token else {
+        return Err(Error::NotFound("Metrics are disabled".to_string()));
+    };
+
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    if ring::constant_time::verify_slices_are_equal(token.as_bytes(), metrics_token.as_bytes())
+        .is_err()
+    {
+        return Err(Error::Forbidden("Invalid metrics token".to_string()));
+    }
+
+    let onboardings = app_state
+        .onboarding_counts
+        .get(&app_state.pool)
+        .await
+        .map_err(|e| Error::Internal(format!("Could not count onboardings: {}", e)))?;
+
+    Ok((
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS.render(&onboardings),
+    ))
+}
+
+/// Middleware recording the latency of every request, must be a route layer so the route is known
+pub async fn track_requests(req: Request<Body>, next: Next<Body>) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let method = req.method().clone();
+    let start = Instant::now();
+
+    let response = next.run(req).await;
+    let status = response.status();
+
+    METRICS.observe_request(method.as_str(), &route, status.as_u16(), start.elapsed());
+
+    if matches!(route.as_str(), "/confirm-login" | "/exchange-code")
+        && (status.is_client_error() || status.is_server_error())
+    {
+        METRICS.login_failures.inc(&route);
+    }
+
+    response
+}
@@ -0,0 +1,212 @@
+use std::{
+    fs::File, future::Future, io::BufReader, net::SocketAddr, path::Path, pin::Pin, sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    extract::connect_info::Connected,
+    http::{HeaderValue, Method},
+    Router,
+};
+use hyper::server::{accept, conn::AddrStream};
+use log::{debug, info, warn};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio_rustls::{rustls, server::TlsStream, TlsAcceptor};
+use tokio_stream::wrappers::{ReceiverStream, UnixListenerStream};
+use tower_http::cors::{Any, CorsLayer};
+
+use crate::config::{RpcServer, Tls};
+
+/// Address of the client of a connection, unknown for Unix sockets
+#[derive(Clone, Copy)]
+pub struct PeerAddr(pub Option<SocketAddr>);
+
+impl Connected<&AddrStream> for PeerAddr {
+    fn connect_info(target: &AddrStream) -> Self {
+        PeerAddr(Some(target.remote_addr()))
+    }
+}
+
+impl Connected<&TlsStream<TcpStream>> for PeerAddr {
+    fn connect_info(target: &TlsStream<TcpStream>) -> Self {
+        PeerAddr(target.get_ref().0.peer_addr().ok())
+    }
+}
+
+impl Connected<&UnixStream> for PeerAddr {
+    fn connect_info(_: &UnixStream) -> Self {
+        PeerAddr(None)
+    }
+}
+
+/// Builds the CORS layer from ``cors_origins`` and ``cors_methods``
+pub fn cors_layer(cfg: &RpcServer) -> Result<CorsLayer, crate::Error> {
+    let layer = CorsLayer::new().allow_headers(Any);
+
+    let layer = if cfg.cors_origins.iter().any(|o| o == "*") {
+        layer.allow_origin(Any)
+    } else {
+        layer.allow_origin(
+            cfg.cors_origins
+                .iter()
+                .map(|o| HeaderValue::from_str(o))
+                .collect::<Result<Vec<_>, _>>()?,
+        )
+    };
+
+    let layer = if cfg.cors_methods.iter().any(|m| m == "*") {
+        layer.allow_methods(Any)
+    } else {
+        layer.allow_methods(
+            cfg.cors_methods
+                .iter()
+                .map(|m| Method::from_bytes(m.to_uppercase().as_bytes()))
+                .collect::<Result<Vec<_>, _>>()?,
+        )
+    };
+
+    Ok(layer)
+}
+
+fn load_tls(tls: &Tls) -> Result<TlsAcceptor, crate::Error> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&tls.cert_path)?))?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(&tls.key_path)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or("No private key found in the TLS key file")?;
+
+    let mut config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+type ServeFuture = Pin<Box<dyn Future<Output = Result<(), hyper::Error>> + Send>>;
+
+fn serve_tcp(app: Router, addr: SocketAddr) -> ServeFuture {
+    info!("Starting RPC server on {}", addr);
+
+    Box::pin(axum::Server::bind(&addr).serve(app.into_make_service_with_connect_info::<PeerAddr>()))
+}
+
+/// How long to wait before accepting again after accepting a connection fails, doubled on every
+/// failure in a row up to [`ACCEPT_BACKOFF_MAX`]
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// How long a client has to finish the TLS handshake before its connection is dropped
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn serve_tls(app: Router, listener: TcpListener, acceptor: TlsAcceptor) -> ServeFuture {
+    let (tx, rx) = tokio::sync::mpsc::channel(64);
+
+    // Handshakes are done in their own tasks so a slow client can't hold up other connections
+    tokio::spawn(async move {
+        let mut backoff = ACCEPT_BACKOFF_MIN;
+
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                // The server stopped, so nothing would pick up new connections
+                _ = tx.closed() => break,
+            };
+
+            let (stream, addr) = match accepted {
+                Ok(conn) => {
+                    backoff = ACCEPT_BACKOFF_MIN;
+                    conn
+                }
+                Err(e) => {
+                    // Errors such as running out of file descriptors persist for a while, retrying
+                    // right away would spin
+                    warn!(
+                        "Could not accept TLS connection, retrying in {:?}: {}",
+                        backoff, e
+                    );
+
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                    continue;
+                }
+            };
+
+            let acceptor = acceptor.clone();
+            let tx = tx.clone();
+
+            tokio::spawn(async move {
+                // Clients that never finish the handshake would otherwise hold on to their task and
+                // socket forever
+                match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = tx.send(Ok::<_, std::io::Error>(stream)).await;
+                    }
+                    Ok(Err(e)) => debug!("TLS handshake with {} failed: {}", addr, e),
+                    Err(_) => debug!("TLS handshake with {} timed out", addr),
+                }
+            });
+        }
+    });
+
+    Box::pin(
+        axum::Server::builder(accept::from_stream(ReceiverStream::new(rx)))
+            .serve(app.into_make_service_with_connect_info::<PeerAddr>()),
+    )
+}
+
+fn serve_unix(app: Router, path: &Path) -> Result<ServeFuture, crate::Error> {
+    // Remove the socket left behind by a previous run
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+
+    info!("Starting RPC server on unix:{}", path.display());
+
+    Ok(Box::pin(
+        axum::Server::builder(accept::from_stream(UnixListenerStream::new(listener)))
+            .serve(app.into_make_service_with_connect_info::<PeerAddr>()),
+    ))
+}
+
+/// Serves the app on every configured address until one of them fails
+pub async fn serve(app: Router, cfg: &RpcServer) -> Result<(), crate::Error> {
+    let mut servers = Vec::new();
+
+    let acceptor = cfg.tls.as_ref().map(load_tls).transpose()?;
+
+    for addr in &cfg.listen {
+        match &acceptor {
+            Some(acceptor) => {
+                info!("Starting RPC server on {} (TLS)", addr);
+
+                let listener = TcpListener::bind(addr).await?;
+                servers.push(serve_tls(app.clone(), listener, acceptor.clone()));
+            }
+            None => servers.push(serve_tcp(app.clone(), *addr)),
+        }
+    }
+
+    if let Some(path) = &cfg.unix_socket {
+        servers.push(serve_unix(app, path)?);
+    }
+
+    let (res, _, _) = futures_util::future::select_all(servers).await;
+
+    res?;
+
+    Err("RPC server stopped".into())
+}
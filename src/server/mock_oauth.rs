@@ -0,0 +1,245 @@
+use std::{collections::HashMap, sync::Arc, sync::Mutex};
+
+use axum::{
+    async_trait,
+    extract::Query,
+    response::{IntoResponse, Redirect},
+};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serenity::all::UserId;
+use sqlx::types::chrono;
+use ts_rs::TS;
+
+use super::error::Error;
+use super::oauth::{redirect_uri, OAuthProvider};
+use super::openapi::{RouteDoc, Routes};
+use super::types::oauth2::{AccessTokenResponse, OAuthUser};
+use crate::config::CONFIG;
+
+/// How long an authorization code can be exchanged for after the authorize page issues it
+const CODE_TTL_SECS: i64 = 60;
+
+/// How long an access token can be used to look up its user, persepolis only does so right after
+/// exchanging the code
+const ACCESS_TOKEN_TTL_SECS: i64 = 600;
+
+struct IssuedCode {
+    user_id: UserId,
+    scope: String,
+    expires_at: i64,
+}
+
+struct IssuedToken {
+    user_id: UserId,
+    expires_at: i64,
+}
+
+/// In-process provider for local development, authorizing immediately logs in as any user
+///
+/// Only built with the ``mock-oauth`` feature. Its authorize page is served by persepolis itself
+/// at ``/mock-oauth2/authorize``. Access tokens it issues are not known to Discord, so joining
+/// onboarding servers does not work through it
+#[derive(Default)]
+pub struct MockProvider {
+    /// Authorization codes that have not been exchanged yet
+    codes: Mutex<HashMap<String, IssuedCode>>,
+    /// Issued access tokens
+    tokens: Mutex<HashMap<String, IssuedToken>>,
+}
+
+/// Query string of the mock authorize page
+#[derive(Deserialize, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/MockAuthorize.ts")]
+pub struct MockAuthorize {
+    scope: String,
+    state: String,
+    /// User to login as, defaults to ``oauth.mock_user_id``
+    #[ts(type = "string | null")]
+    #[schemars(with = "Option<String>")]
+    user_id: Option<UserId>,
+}
+
+impl MockProvider {
+    fn authorize(&self, req: MockAuthorize) -> Result<impl IntoResponse, Error> {
+        let user_id = req.user_id.or(CONFIG.oauth.mock_user_id).ok_or_else(|| {
+            Error::InvalidRequest("No user_id given and no oauth.mock_user_id set".to_string())
+        })?;
+
+        let code = botox::crypto::gen_random(32);
+        let now = chrono::Utc::now().timestamp();
+
+        {
+            let mut codes = self.codes.lock().unwrap_or_else(|e| e.into_inner());
+
+            codes.retain(|_, c| c.expires_at >= now);
+
+            codes.insert(
+                code.clone(),
+                IssuedCode {
+                    user_id,
+                    scope: req.scope,
+                    expires_at: now + CODE_TTL_SECS,
+                },
+            );
+        }
+
+        // The state comes straight from the query string, so it must be encoded to not be able to
+        // add parameters of its own
+        let url = reqwest::Url::parse_with_params(
+            &redirect_uri(),
+            &[("code", code.as_str()), ("state", req.state.as_str())],
+        )
+        .map_err(|e| Error::Internal(format!("Invalid redirect URI: {}", e)))?;
+
+        Ok(Redirect::temporary(url.as_str()))
+    }
+}
+
+#[async_trait]
+impl OAuthProvider for MockProvider {
+    fn authorize_url(&self, client_id: &str, scopes: &[&str], state: &str) -> String {
+        format!(
+            "{}/mock-oauth2/authorize?client_id={}&scope={}&state={}&response_type=code",
+            CONFIG.persepolis_domain,
+            client_id,
+            scopes.join("%20"),
+            state
+        )
+    }
+
+    async fn exchange_code(&self, _: &str, code: &str) -> Result<AccessTokenResponse, Error> {
+        let now = chrono::Utc::now().timestamp();
+
+        let issued = self
+            .codes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(code)
+            .filter(|c| c.expires_at >= now)
+            .ok_or_else(|| {
+                Error::LoginFailed(
+                    "Could not get access token: unknown or expired code".to_string(),
+                )
+            })?;
+
+        let access_token = format!("mock.{}", botox::crypto::gen_random(32));
+
+        {
+            let mut tokens = self.tokens.lock().unwrap_or_else(|e| e.into_inner());
+
+            tokens.retain(|_, t| t.expires_at >= now);
+
+            tokens.insert(
+                access_token.clone(),
+                IssuedToken {
+                    user_id: issued.user_id,
+                    expires_at: now + ACCESS_TOKEN_TTL_SECS,
+                },
+            );
+        }
+
+        Ok(AccessTokenResponse {
+            access_token,
+            scope: issued.scope,
+        })
+    }
+
+    async fn get_user(&self, access_token: &str) -> Result<OAuthUser, Error> {
+        let id = self
+            .tokens
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(access_token)
+            .filter(|t| t.expires_at >= chrono::Utc::now().timestamp())
+            .map(|t| t.user_id)
+            .ok_or_else(|| Error::LoginFailed("Get User failed!".to_string()))?;
+
+        Ok(OAuthUser { id })
+    }
+
+    fn routes(self: Arc<Self>) -> Routes {
+        Routes::new(false).get(
+            "/mock-oauth2/authorize",
+            RouteDoc::new("Authorize page of the mock OAuth2 provider, redirects back to /confirm-login right away")
+                .request::<MockAuthorize>()
+                .redirect(),
+            move |Query(req): Query<MockAuthorize>| async move { self.authorize(req) },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header::LOCATION;
+
+    use super::*;
+
+    fn authorize(mock: &MockProvider, state: &str) -> reqwest::Url {
+        let res = mock
+            .authorize(MockAuthorize {
+                scope: "identify".to_string(),
+                state: state.to_string(),
+                user_id: Some(UserId::new(563808552288780322)),
+            })
+            .unwrap_or_else(|_| panic!("authorize failed"))
+            .into_response();
+
+        reqwest::Url::parse(res.headers()[LOCATION].to_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn state_is_encoded() {
+        let url = authorize(&MockProvider::default(), "a&code=b c");
+
+        let pairs = url.query_pairs().collect::<Vec<_>>();
+
+        assert_eq!(pairs.len(), 2);
+        assert!(pairs.iter().any(|(k, v)| k == "state" && v == "a&code=b c"));
+    }
+
+    #[tokio::test]
+    async fn codes_can_only_be_exchanged_once() {
+        let mock = MockProvider::default();
+        let url = authorize(&mock, "state");
+
+        let (_, code) = url.query_pairs().find(|(k, _)| k == "code").unwrap();
+
+        let token = mock
+            .exchange_code("", &code)
+            .await
+            .unwrap_or_else(|_| panic!("exchange failed"));
+        assert_eq!(
+            mock.get_user(&token.access_token).await.ok().map(|u| u.id),
+            Some(UserId::new(563808552288780322))
+        );
+
+        assert!(mock.exchange_code("", &code).await.is_err());
+        assert!(mock.codes.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn expired_codes_are_rejected_and_pruned() {
+        let mock = MockProvider::default();
+        let url = authorize(&mock, "state");
+
+        let (_, code) = url.query_pairs().find(|(k, _)| k == "code").unwrap();
+
+        for issued in mock.codes.lock().unwrap().values_mut() {
+            issued.expires_at -= CODE_TTL_SECS + 1;
+        }
+
+        assert!(mock.exchange_code("", &code).await.is_err());
+
+        // Issuing another code drops the ones that expired
+        authorize(&mock, "state");
+        authorize(&mock, "state");
+
+        for issued in mock.codes.lock().unwrap().values_mut() {
+            issued.expires_at -= CODE_TTL_SECS + 1;
+        }
+
+        authorize(&mock, "state");
+        assert_eq!(mock.codes.lock().unwrap().len(), 1);
+    }
+}
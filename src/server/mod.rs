@@ -1,3 +1,12 @@
 pub mod api;
+pub mod error;
+pub mod extract;
 pub mod types;
-pub mod auth;
\ No newline at end of file
+pub mod auth;
+pub mod oauth;
+#[cfg(any(test, feature = "mock-oauth"))]
+pub mod mock_oauth;
+pub mod ratelimit;
+pub mod listen;
+pub mod openapi;
+pub mod health;
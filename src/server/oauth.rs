@@ -0,0 +1,104 @@
+use std::sync::Arc;
+
+use axum::async_trait;
+use serde_json::json;
+
+use super::error::Error;
+use super::openapi::Routes;
+use super::types::oauth2::{AccessTokenResponse, OAuthUser};
+use crate::config::CONFIG;
+
+/// Where the OAuth2 provider sends users back to after they authorize persepolis
+pub fn redirect_uri() -> String {
+    format!("{}/confirm-login", CONFIG.persepolis_domain)
+}
+
+/// An OAuth2 provider users can login through
+#[async_trait]
+pub trait OAuthProvider: Send + Sync {
+    /// Returns the URL to send users to so they can authorize persepolis
+    fn authorize_url(&self, client_id: &str, scopes: &[&str], state: &str) -> String;
+
+    /// Exchanges an authorization code for an access token
+    async fn exchange_code(
+        &self,
+        client_id: &str,
+        code: &str,
+    ) -> Result<AccessTokenResponse, Error>;
+
+    /// Returns the user an access token belongs to
+    async fn get_user(&self, access_token: &str) -> Result<OAuthUser, Error>;
+
+    /// Routes the provider serves from persepolis itself, such as an authorize page
+    fn routes(self: Arc<Self>) -> Routes {
+        Routes::new(false)
+    }
+}
+
+/// Logins through Discord, all requests share one client
+pub struct DiscordProvider {
+    client: reqwest::Client,
+    /// Base URL of the Discord API, without a trailing slash
+    api_url: String,
+}
+
+impl DiscordProvider {
+    pub fn new(client: reqwest::Client, api_url: &str) -> Self {
+        Self {
+            client,
+            api_url: api_url.trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl OAuthProvider for DiscordProvider {
+    fn authorize_url(&self, client_id: &str, scopes: &[&str], state: &str) -> String {
+        format!(
+            "{}/oauth2/authorize?client_id={}&redirect_uri={}&scope={}&state={}&response_type=code",
+            self.api_url,
+            client_id,
+            redirect_uri(),
+            scopes.join("%20"),
+            state
+        )
+    }
+
+    async fn exchange_code(
+        &self,
+        client_id: &str,
+        code: &str,
+    ) -> Result<AccessTokenResponse, Error> {
+        self.client
+            .post(format!("{}/oauth2/token", self.api_url))
+            .form(&json!({
+                "client_id": client_id,
+                "client_secret": CONFIG.client_secret,
+                "grant_type": "authorization_code",
+                "code": code,
+                "redirect_uri": redirect_uri(),
+            }))
+            .send()
+            .await
+            .map_err(|_| Error::Internal("Could not send request to get access token".to_string()))?
+            .error_for_status()
+            .map_err(|e| Error::LoginFailed(format!("Could not get access token: {}", e)))?
+            .json::<AccessTokenResponse>()
+            .await
+            .map_err(|_| Error::Internal("Could not deserialize response".to_string()))
+    }
+
+    async fn get_user(&self, access_token: &str) -> Result<OAuthUser, Error> {
+        self.client
+            .get(format!("{}/users/@me", self.api_url))
+            .header("Authorization", format!("Bearer {}", access_token))
+            .send()
+            .await
+            .map_err(|_| Error::Internal("Could not send request to get user".to_string()))?
+            .error_for_status()
+            .map_err(|_| Error::LoginFailed("Get User failed!".to_string()))?
+            .json::<OAuthUser>()
+            .await
+            .map_err(|_| Error::Internal("Could not deserialize response".to_string()))
+    }
+}
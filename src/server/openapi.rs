@@ -0,0 +1,402 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{
+    body::Body,
+    handler::Handler,
+    http::Method,
+    routing::{get, post},
+    Json, Router,
+};
+use schemars::{
+    gen::{SchemaGenerator, SchemaSettings},
+    JsonSchema,
+};
+use serde_json::{json, Map, Value};
+
+use super::{
+    api::AppState,
+    error::{ApiErrorCode, ApiErrorResponse},
+    extract::Permission,
+};
+
+/// A type used in the API, described by its ``schemars`` schema
+struct Schema {
+    name: String,
+    /// Returns a reference to the schema of the type, adding it to the components of the document,
+    /// or the schema itself for types such as numbers
+    subschema: fn(&mut SchemaGenerator) -> schemars::schema::Schema,
+}
+
+impl Schema {
+    fn of<T: JsonSchema>() -> Self {
+        Self {
+            name: T::schema_name(),
+            subschema: |gen| gen.subschema_for::<T>(),
+        }
+    }
+
+    fn to_json(&self, gen: &mut SchemaGenerator) -> Value {
+        let schema = (self.subschema)(gen);
+        finish_schema(gen, schema)
+    }
+}
+
+/// Applies the OpenAPI 3 visitors of the generator, ``schemars`` only applies them to root schemas
+fn finish_schema(gen: &mut SchemaGenerator, mut schema: schemars::schema::Schema) -> Value {
+    for visitor in gen.visitors_mut() {
+        visitor.visit_schema(&mut schema);
+    }
+
+    serde_json::to_value(schema).unwrap_or_default()
+}
+
+enum Success {
+    Json(Schema),
+    Text,
+    NoContent,
+    Redirect,
+}
+
+/// Documentation of a route
+pub struct RouteDoc {
+    summary: &'static str,
+    request: Option<Schema>,
+    success: Success,
+    permission: Option<&'static str>,
+    errors: Vec<ApiErrorCode>,
+}
+
+impl RouteDoc {
+    pub fn new(summary: &'static str) -> Self {
+        Self {
+            summary,
+            request: None,
+            success: Success::NoContent,
+            permission: None,
+            errors: vec![],
+        }
+    }
+
+    /// The payload of the route, read from the query string for ``GET`` and the body otherwise
+    pub fn request<T: JsonSchema>(mut self) -> Self {
+        self.request = Some(Schema::of::<T>());
+        self
+    }
+
+    /// Responds with a type as JSON
+    pub fn json<T: JsonSchema>(mut self) -> Self {
+        self.success = Success::Json(Schema::of::<T>());
+        self
+    }
+
+    /// Responds with a list of a type as JSON
+    pub fn json_list<T: JsonSchema>(mut self) -> Self {
+        self.success = Success::Json(Schema::of::<Vec<T>>());
+        self
+    }
+
+    pub fn text(mut self) -> Self {
+        self.success = Success::Text;
+        self
+    }
+
+    pub fn redirect(mut self) -> Self {
+        self.success = Success::Redirect;
+        self
+    }
+
+    /// The ``persepolis`` permission the route requires
+    pub fn permission<P: Permission>(mut self) -> Self {
+        self.permission = Some(P::NAME);
+        self
+    }
+
+    /// Errors the route can return besides the ones every route can
+    pub fn errors(mut self, errors: &[ApiErrorCode]) -> Self {
+        self.errors.extend_from_slice(errors);
+        self
+    }
+}
+
+struct Operation {
+    path: &'static str,
+    method: Method,
+    authed: bool,
+    doc: Arc<RouteDoc>,
+}
+
+/// Route table of the API, every route added through it is documented in ``/openapi.json``
+pub struct Routes {
+    router: Router<Arc<AppState>>,
+    operations: Vec<Operation>,
+    authed: bool,
+}
+
+impl Routes {
+    /// ``authed`` must match whether the routes are put behind the auth middleware
+    pub fn new(authed: bool) -> Self {
+        Self {
+            router: Router::new(),
+            operations: vec![],
+            authed,
+        }
+    }
+
+    fn document(&mut self, path: &'static str, methods: &[Method], doc: RouteDoc) {
+        let doc = Arc::new(doc);
+
+        for method in methods {
+            self.operations.push(Operation {
+                path,
+                method: method.clone(),
+                authed: self.authed,
+                doc: doc.clone(),
+            });
+        }
+    }
+
+    pub fn get<H, T>(mut self, path: &'static str, doc: RouteDoc, handler: H) -> Self
+    where
+        H: Handler<T, Arc<AppState>, Body>,
+        T: 'static,
+    {
+        self.document(path, &[Method::GET], doc);
+        self.router = self.router.route(path, get(handler));
+        self
+    }
+
+    pub fn post<H, T>(mut self, path: &'static str, doc: RouteDoc, handler: H) -> Self
+    where
+        H: Handler<T, Arc<AppState>, Body>,
+        T: 'static,
+    {
+        self.document(path, &[Method::POST], doc);
+        self.router = self.router.route(path, post(handler));
+        self
+    }
+
+    /// Routes accepting both ``GET`` with a query string and ``POST`` with a JSON body
+    pub fn get_post<H, T>(mut self, path: &'static str, doc: RouteDoc, handler: H) -> Self
+    where
+        H: Handler<T, Arc<AppState>, Body>,
+        T: 'static,
+    {
+        self.document(path, &[Method::GET, Method::POST], doc);
+        self.router = self.router.route(path, get(handler.clone()).post(handler));
+        self
+    }
+
+    /// Merges another route table into this one
+    pub fn merge(mut self, other: Routes) -> Self {
+        self.router = self.router.merge(other.router);
+        self.operations.extend(other.operations);
+        self
+    }
+
+    /// Paths and methods of the routes added so far
+    #[cfg(test)]
+    pub fn operations(&self) -> Vec<(&'static str, Method)> {
+        self.operations
+            .iter()
+            .map(|op| (op.path, op.method.clone()))
+            .collect()
+    }
+
+    /// Returns the router, serving the OpenAPI document of the routes at ``/openapi.json``
+    pub fn finish(mut self) -> Router<Arc<AppState>> {
+        self.document(
+            "/openapi.json",
+            &[Method::GET],
+            RouteDoc::new("This document").json::<Map<String, Value>>(),
+        );
+
+        let doc = Arc::new(openapi(&self.operations));
+
+        self.router.route(
+            "/openapi.json",
+            get(move || async move { Json(doc.as_ref().clone()) }),
+        )
+    }
+
+    /// Applies ``f`` to the router, used for route layers
+    pub fn map_router(
+        mut self,
+        f: impl FnOnce(Router<Arc<AppState>>) -> Router<Arc<AppState>>,
+    ) -> Self {
+        self.router = f(self.router);
+        self
+    }
+}
+
+fn error_code_name(code: ApiErrorCode) -> String {
+    serde_json::to_value(code)
+        .ok()
+        .and_then(|v| v.as_str().map(|s| s.to_string()))
+        .unwrap_or_default()
+}
+
+fn operation(op: &Operation, gen: &mut SchemaGenerator) -> Value {
+    let doc = &op.doc;
+
+    let mut operation = Map::new();
+
+    operation.insert("summary".to_string(), json!(doc.summary));
+
+    let mut errors = vec![ApiErrorCode::RateLimited, ApiErrorCode::Internal];
+
+    if let Some(request) = &doc.request {
+        errors.push(ApiErrorCode::InvalidRequest);
+
+        if op.method == Method::GET {
+            operation.insert(
+                "parameters".to_string(),
+                json!([{
+                    "name": request.name,
+                    "in": "query",
+                    "required": true,
+                    "style": "form",
+                    "explode": true,
+                    "schema": request.to_json(gen),
+                }]),
+            );
+        } else {
+            operation.insert(
+                "requestBody".to_string(),
+                json!({
+                    "required": true,
+                    "content": { "application/json": { "schema": request.to_json(gen) } },
+                }),
+            );
+        }
+    }
+
+    if op.authed {
+        errors.push(ApiErrorCode::IdentityExpired);
+        operation.insert("security".to_string(), json!([{ "session": [] }]));
+    }
+
+    if let Some(perm) = doc.permission {
+        errors.push(ApiErrorCode::MissingPermission);
+        operation.insert(
+            "description".to_string(),
+            json!(format!("Requires the `persepolis.{}` permission", perm)),
+        );
+    }
+
+    errors.extend(doc.errors.iter().copied());
+
+    let mut responses = Map::new();
+
+    let (status, success) = match &doc.success {
+        Success::Json(schema) => (
+            "200",
+            json!({
+                "description": "Success",
+                "content": { "application/json": { "schema": schema.to_json(gen) } },
+            }),
+        ),
+        Success::Text => (
+            "200",
+            json!({
+                "description": "Success",
+                "content": { "text/plain": { "schema": { "type": "string" } } },
+            }),
+        ),
+        Success::NoContent => ("204", json!({ "description": "Success" })),
+        Success::Redirect => (
+            "307",
+            json!({
+                "description": "Redirect",
+                "headers": { "Location": { "schema": { "type": "string" } } },
+            }),
+        ),
+    };
+
+    responses.insert(status.to_string(), success);
+
+    // Errors are grouped by their status
+    let mut by_status: BTreeMap<u16, Vec<String>> = BTreeMap::new();
+
+    for code in errors {
+        let names = by_status.entry(code.status().as_u16()).or_default();
+        let name = error_code_name(code);
+
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+
+    for (status, codes) in by_status {
+        let mut response = json!({
+            "description": format!("Error codes: {}", codes.join(", ")),
+            "content": {
+                "application/json": { "schema": { "$ref": "#/components/schemas/ApiError" } },
+            },
+        });
+
+        if status == 429 {
+            response["headers"] = json!({
+                "Retry-After": {
+                    "description": "Seconds to wait before retrying, only set for rateLimited",
+                    "schema": { "type": "integer" },
+                },
+            });
+        }
+
+        responses.insert(status.to_string(), response);
+    }
+
+    operation.insert("responses".to_string(), Value::Object(responses));
+
+    let policy = super::ratelimit::policy_for(op.path);
+
+    operation.insert(
+        "x-rate-limit".to_string(),
+        json!({ "burst": policy.burst, "refill_secs": policy.refill_secs }),
+    );
+
+    Value::Object(operation)
+}
+
+fn openapi(operations: &[Operation]) -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+
+    // Referenced by every error response
+    gen.subschema_for::<ApiErrorResponse>();
+
+    let mut paths: BTreeMap<&str, Map<String, Value>> = BTreeMap::new();
+
+    for op in operations {
+        let operation = operation(op, &mut gen);
+
+        paths
+            .entry(op.path)
+            .or_default()
+            .insert(op.method.as_str().to_lowercase(), operation);
+    }
+
+    let schemas = gen
+        .take_definitions()
+        .into_iter()
+        .map(|(name, schema)| (name, finish_schema(&mut gen, schema)))
+        .collect::<BTreeMap<_, _>>();
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Persepolis RPC",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "session": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "Session token from /exchange-code or /refresh-session",
+                },
+            },
+        },
+    })
+}
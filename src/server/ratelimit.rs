@@ -0,0 +1,392 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, State},
+    http::Request,
+    middleware::Next,
+    response::Response,
+};
+
+use super::{api::AppState, error::Error, listen::PeerAddr, types::auth::AuthData};
+use crate::config::CONFIG;
+
+/// How often buckets that have refilled completely are dropped, a full bucket is the same as no
+/// bucket
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Most buckets kept at once. Once reached, the least recently seen tenth is evicted so a spray
+/// from many IPs can't grow the map without bound
+const MAX_BUCKETS: usize = 100_000;
+
+/// A token bucket rate limit
+pub struct Policy {
+    pub name: &'static str,
+    /// Maximum number of requests that can be made at once
+    pub burst: u32,
+    /// Seconds it takes for one request to become available again
+    pub refill_secs: f64,
+}
+
+/// Limit of routes without a stricter policy
+pub const DEFAULT: Policy = Policy {
+    name: "default",
+    burst: 120,
+    refill_secs: 0.5,
+};
+
+/// Every submission can be a guess of the staff verification code
+pub const SUBMIT_QUIZ: Policy = Policy {
+    name: "submit_quiz",
+    burst: 5,
+    refill_secs: 60.0,
+};
+
+pub const CONFIRM_LOGIN: Policy = Policy {
+    name: "confirm_login",
+    burst: 10,
+    refill_secs: 30.0,
+};
+
+pub const AUTH_DATA: Policy = Policy {
+    name: "auth_data",
+    burst: 30,
+    refill_secs: 2.0,
+};
+
+/// Returns the policy of a route
+pub fn policy_for(path: &str) -> &'static Policy {
+    match path {
+        "/submit-quiz" => &SUBMIT_QUIZ,
+        "/confirm-login" => &CONFIRM_LOGIN,
+        "/auth-data" => &AUTH_DATA,
+        _ => &DEFAULT,
+    }
+}
+
+#[derive(Hash, PartialEq, Eq)]
+enum Key {
+    Ip(IpAddr),
+    /// Every client whose IP is not known, such as requests over the Unix socket that did not come
+    /// with ``X-Forwarded-For``
+    UnknownIp,
+    User(String),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Refills the bucket up to now
+    fn refill(&mut self, policy: &Policy, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed / policy.refill_secs).min(policy.burst as f64);
+        self.updated = now;
+    }
+
+    /// Whether the bucket would be full if it was refilled now
+    fn is_full(&self, policy: &Policy, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+
+        self.tokens + elapsed / policy.refill_secs >= policy.burst as f64
+    }
+}
+
+struct Buckets {
+    buckets: HashMap<(&'static str, Key), Bucket>,
+    last_pruned: Instant,
+}
+
+impl Buckets {
+    /// Drops buckets that have refilled completely
+    fn prune(&mut self, now: Instant) {
+        // Buckets are not refilled here, that would make them look recently seen
+        self.buckets
+            .retain(|(name, _), bucket| !bucket.is_full(policy_by_name(name), now));
+
+        self.last_pruned = now;
+    }
+
+    /// Evicts the least recently seen tenth of the buckets
+    fn evict(&mut self) {
+        let mut updated = self.buckets.values().map(|b| b.updated).collect::<Vec<_>>();
+
+        let tenth = updated.len() / 10;
+        let (_, cutoff, _) = updated.select_nth_unstable(tenth);
+        let cutoff = *cutoff;
+
+        self.buckets.retain(|_, bucket| bucket.updated > cutoff);
+    }
+}
+
+/// Token buckets of every client, per policy
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self {
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                last_pruned: Instant::now(),
+            }),
+        }
+    }
+}
+
+impl RateLimiter {
+    /// Takes a request from the bucket of ``key``, returning the seconds to wait if it is empty
+    fn take(&self, policy: &'static Policy, key: Key) -> Result<(), u64> {
+        self.take_at(policy, key, Instant::now())
+    }
+
+    fn take_at(&self, policy: &'static Policy, key: Key, now: Instant) -> Result<(), u64> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+
+        if now.duration_since(buckets.last_pruned) >= PRUNE_INTERVAL {
+            buckets.prune(now);
+        }
+
+        let key = (policy.name, key);
+
+        if buckets.buckets.len() >= MAX_BUCKETS && !buckets.buckets.contains_key(&key) {
+            buckets.evict();
+        }
+
+        let bucket = buckets.buckets.entry(key).or_insert(Bucket {
+            tokens: policy.burst as f64,
+            updated: now,
+        });
+
+        bucket.refill(policy, now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) * policy.refill_secs).ceil() as u64)
+        }
+    }
+}
+
+fn policy_by_name(name: &str) -> &'static Policy {
+    [&SUBMIT_QUIZ, &CONFIRM_LOGIN, &AUTH_DATA]
+        .into_iter()
+        .find(|p| p.name == name)
+        .unwrap_or(&DEFAULT)
+}
+
+/// Returns the IP of the client, from ``X-Forwarded-For`` if ``trust_forwarded_for`` is set
+fn client_ip(req: &Request<Body>) -> Option<IpAddr> {
+    if CONFIG.trust_forwarded_for {
+        // The last entry is the one added by our own proxy
+        let forwarded = req
+            .headers()
+            .get("X-Forwarded-For")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse().ok());
+
+        if forwarded.is_some() {
+            return forwarded;
+        }
+    }
+
+    req.extensions()
+        .get::<ConnectInfo<PeerAddr>>()
+        .and_then(|ConnectInfo(PeerAddr(addr))| addr.map(|a| a.ip()))
+}
+
+/// Middleware limiting the requests of every IP, clients without a known IP share one bucket
+pub async fn limit_ip(
+    State(app_state): State<Arc<AppState>>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, Error> {
+    let key = client_ip(&req).map_or(Key::UnknownIp, Key::Ip);
+
+    app_state
+        .rate_limiter
+        .take(policy_for(req.uri().path()), key)
+        .map_err(Error::RateLimited)?;
+
+    Ok(next.run(req).await)
+}
+
+/// Middleware limiting the requests of every user, must run after [`super::extract::require_auth`]
+pub async fn limit_user(
+    State(app_state): State<Arc<AppState>>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, Error> {
+    if let Some(auth_data) = req.extensions().get::<AuthData>() {
+        app_state
+            .rate_limiter
+            .take(
+                policy_for(req.uri().path()),
+                Key::User(auth_data.user_id.clone()),
+            )
+            .map_err(Error::RateLimited)?;
+    }
+
+    Ok(next.run(req).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> Key {
+        Key::Ip(IpAddr::from([10, 0, 0, last]))
+    }
+
+    fn secs(start: Instant, secs: f64) -> Instant {
+        start + Duration::from_secs_f64(secs)
+    }
+
+    #[test]
+    fn empty_buckets_are_rejected_with_retry_after() {
+        let limiter = RateLimiter::default();
+        let now = Instant::now();
+
+        for _ in 0..SUBMIT_QUIZ.burst {
+            assert_eq!(limiter.take_at(&SUBMIT_QUIZ, ip(1), now), Ok(()));
+        }
+
+        assert_eq!(
+            limiter.take_at(&SUBMIT_QUIZ, ip(1), now),
+            Err(SUBMIT_QUIZ.refill_secs as u64)
+        );
+
+        // Half a request has refilled
+        assert_eq!(
+            limiter.take_at(
+                &SUBMIT_QUIZ,
+                ip(1),
+                secs(now, SUBMIT_QUIZ.refill_secs / 2.0)
+            ),
+            Err((SUBMIT_QUIZ.refill_secs / 2.0) as u64)
+        );
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let limiter = RateLimiter::default();
+        let now = Instant::now();
+
+        for _ in 0..CONFIRM_LOGIN.burst {
+            assert_eq!(limiter.take_at(&CONFIRM_LOGIN, ip(1), now), Ok(()));
+        }
+
+        assert!(limiter.take_at(&CONFIRM_LOGIN, ip(1), now).is_err());
+
+        // One request per refill_secs
+        let later = secs(now, CONFIRM_LOGIN.refill_secs);
+        assert_eq!(limiter.take_at(&CONFIRM_LOGIN, ip(1), later), Ok(()));
+        assert!(limiter.take_at(&CONFIRM_LOGIN, ip(1), later).is_err());
+
+        // Never past the burst
+        let much_later = secs(now, CONFIRM_LOGIN.refill_secs * 1000.0);
+
+        for _ in 0..CONFIRM_LOGIN.burst {
+            assert_eq!(limiter.take_at(&CONFIRM_LOGIN, ip(1), much_later), Ok(()));
+        }
+
+        assert!(limiter.take_at(&CONFIRM_LOGIN, ip(1), much_later).is_err());
+    }
+
+    #[test]
+    fn keys_and_policies_have_separate_buckets() {
+        let limiter = RateLimiter::default();
+        let now = Instant::now();
+
+        for _ in 0..SUBMIT_QUIZ.burst {
+            assert_eq!(limiter.take_at(&SUBMIT_QUIZ, ip(1), now), Ok(()));
+        }
+
+        assert!(limiter.take_at(&SUBMIT_QUIZ, ip(1), now).is_err());
+
+        assert_eq!(limiter.take_at(&SUBMIT_QUIZ, ip(2), now), Ok(()));
+        assert_eq!(limiter.take_at(&SUBMIT_QUIZ, Key::UnknownIp, now), Ok(()));
+        assert_eq!(limiter.take_at(&DEFAULT, ip(1), now), Ok(()));
+        assert_eq!(limiter.take_at(&CONFIRM_LOGIN, ip(1), now), Ok(()));
+
+        // A user is limited apart from the IP they connect from
+        let user = || Key::User("563808552288780322".to_string());
+
+        for _ in 0..SUBMIT_QUIZ.burst {
+            assert_eq!(limiter.take_at(&SUBMIT_QUIZ, user(), now), Ok(()));
+        }
+
+        assert!(limiter.take_at(&SUBMIT_QUIZ, user(), now).is_err());
+        assert_eq!(limiter.take_at(&SUBMIT_QUIZ, ip(2), now), Ok(()));
+    }
+
+    #[test]
+    fn refilled_buckets_are_pruned_on_an_interval() {
+        let limiter = RateLimiter::default();
+        let now = Instant::now();
+
+        assert_eq!(limiter.take_at(&DEFAULT, ip(1), now), Ok(()));
+
+        for _ in 0..SUBMIT_QUIZ.burst {
+            assert_eq!(limiter.take_at(&SUBMIT_QUIZ, ip(2), now), Ok(()));
+        }
+
+        // ip(1) refilled long before the interval passes, ip(2) takes minutes to
+        let later = now + PRUNE_INTERVAL;
+        assert_eq!(limiter.take_at(&DEFAULT, ip(3), later), Ok(()));
+
+        let buckets = limiter.buckets.lock().unwrap();
+
+        assert!(!buckets.buckets.contains_key(&(DEFAULT.name, ip(1))));
+        assert!(buckets.buckets.contains_key(&(SUBMIT_QUIZ.name, ip(2))));
+        assert!(buckets.buckets.contains_key(&(DEFAULT.name, ip(3))));
+    }
+
+    #[test]
+    fn least_recently_seen_buckets_are_evicted_at_the_cap() {
+        let limiter = RateLimiter::default();
+        let now = Instant::now();
+
+        for i in 0..MAX_BUCKETS {
+            let key = Key::User(i.to_string());
+            let seen = now + Duration::from_micros(i as u64);
+
+            assert_eq!(limiter.take_at(&SUBMIT_QUIZ, key, seen), Ok(()));
+        }
+
+        let seen = now + Duration::from_micros(MAX_BUCKETS as u64);
+        assert_eq!(limiter.take_at(&SUBMIT_QUIZ, ip(1), seen), Ok(()));
+
+        let buckets = limiter.buckets.lock().unwrap();
+        let key = |i: usize| (SUBMIT_QUIZ.name, Key::User(i.to_string()));
+
+        assert!(buckets.buckets.len() <= MAX_BUCKETS - MAX_BUCKETS / 10 + 1);
+        assert!(!buckets.buckets.contains_key(&key(0)));
+        assert!(!buckets.buckets.contains_key(&key(MAX_BUCKETS / 10 - 1)));
+        assert!(buckets.buckets.contains_key(&key(MAX_BUCKETS - 1)));
+        assert!(buckets.buckets.contains_key(&(SUBMIT_QUIZ.name, ip(1))));
+    }
+
+    #[test]
+    fn routes_have_their_policy() {
+        assert_eq!(policy_for("/submit-quiz").name, SUBMIT_QUIZ.name);
+        assert_eq!(policy_for("/confirm-login").name, CONFIRM_LOGIN.name);
+        assert_eq!(policy_for("/auth-data").name, AUTH_DATA.name);
+        assert_eq!(policy_for("/onboarding").name, DEFAULT.name);
+
+        for policy in [&DEFAULT, &SUBMIT_QUIZ, &CONFIRM_LOGIN, &AUTH_DATA] {
+            assert_eq!(policy_by_name(policy.name).name, policy.name);
+        }
+    }
+}
@@ -1,22 +1,53 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
-#[derive(Serialize, Deserialize, Clone, TS)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
 #[ts(export, export_to = ".generated/AuthData.ts")]
 pub struct AuthData {
     pub user_id: String,
     pub created_at: i64,
     pub state: String,
+    pub session_id: String,
+    /// When the session token expires and must be refreshed
+    pub expires_at: i64,
 }
 
-#[derive(Serialize, Deserialize, Clone, TS)]
-#[ts(export, export_to = ".generated/GetAuthData.ts")]
-pub struct GetAuthData {
-    pub login_token: String,
-}
-
-#[derive(Serialize, Deserialize, Clone, TS)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
 #[ts(export, export_to = ".generated/CreateLogin.ts")]
 pub struct CreateLogin {
     pub state: String,
-}
\ No newline at end of file
+    /// PKCE code challenge (S256) for ``create_session`` states, the verifier must then be sent
+    /// to ``/exchange-code``
+    pub code_challenge: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/ExchangeCode.ts")]
+pub struct ExchangeCode {
+    /// The code the login redirected back with
+    pub code: String,
+    /// Required if a code challenge was given when creating the login
+    pub code_verifier: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/SessionTokens.ts")]
+pub struct SessionTokens {
+    pub token: String,
+    /// Exchanged for new tokens through ``/refresh-session`` once ``token`` expires
+    pub refresh_token: String,
+    pub expires_at: i64,
+    pub refresh_expires_at: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/SessionInfo.ts")]
+pub struct SessionInfo {
+    pub session_id: String,
+    pub created_at: i64,
+    pub expires_at: i64,
+    pub refresh_expires_at: Option<i64>,
+    /// Whether this is the session making the request
+    pub current: bool,
+}
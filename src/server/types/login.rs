@@ -1,10 +1,15 @@
 use serenity::all::UserId;
+use crate::server::oauth::OAuthProvider;
+use sqlx::types::chrono;
+use std::collections::HashMap;
 use std::str::FromStr;
 use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
 
 pub enum ConfirmLoginState {
     JoinOnboardingServer(UserId),
-    CreateSession(String),
+    /// Redirect URL and optional PKCE code challenge
+    CreateSession(String, Option<String>),
 }
 
 impl FromStr for ConfirmLoginState {
@@ -12,20 +17,26 @@ impl FromStr for ConfirmLoginState {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let split = s.split('.').collect::<Vec<&str>>();
 
-        if split.len() != 2 {
+        if split.len() < 2 {
             return Err("Invalid state".into());
         }
 
         match split[0] {
-            "create_session" => {
+            "create_session" if split.len() <= 3 => {
                 // Hex decode the second bit
                 let decoded = data_encoding::HEXLOWER.decode(split[1].as_bytes())?;
 
                 let decoded_str = String::from_utf8(decoded)?;
 
-                Ok(ConfirmLoginState::CreateSession(decoded_str))
+                let code_challenge = split.get(2).map(|c| c.to_string());
+
+                if let Some(code_challenge) = &code_challenge {
+                    validate_code_challenge(code_challenge)?;
+                }
+
+                Ok(ConfirmLoginState::CreateSession(decoded_str, code_challenge))
             },
-            "jos" => {
+            "jos" if split.len() == 2 => {
                 let uid = split[1].parse::<UserId>()?;
 
                 Ok(ConfirmLoginState::JoinOnboardingServer(uid))
@@ -39,31 +50,337 @@ impl Display for ConfirmLoginState {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             ConfirmLoginState::JoinOnboardingServer(uid) => write!(f, "jos.{}", uid),
-            ConfirmLoginState::CreateSession(redirect_url) => {
+            ConfirmLoginState::CreateSession(redirect_url, code_challenge) => {
                 let encoded = data_encoding::HEXLOWER.encode(redirect_url.as_bytes());
-                write!(f, "create_session.{}", encoded)
+                write!(f, "create_session.{}", encoded)?;
+
+                if let Some(code_challenge) = code_challenge {
+                    write!(f, ".{}", code_challenge)?;
+                }
+
+                Ok(())
             },
         }
     }
 }
 
+/// Checks that a PKCE code challenge is an unpadded base64url encoded SHA-256 hash
+pub fn validate_code_challenge(code_challenge: &str) -> Result<(), crate::Error> {
+    if code_challenge.len() != 43
+        || !code_challenge
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err("Invalid code challenge, it must be the S256 challenge of the code verifier".into());
+    }
+
+    Ok(())
+}
+
+/// How long a signed login state is accepted for after being issued
+pub const LOGIN_STATE_TTL_SECS: i64 = 600;
+
 impl ConfirmLoginState {
     /// Returns the scopes needed for this state
     pub fn needed_scopes(&self) -> Vec<&str> {
         match self {
             ConfirmLoginState::JoinOnboardingServer(_) => vec!["identify", "guilds.join"],
-            ConfirmLoginState::CreateSession(_) => vec!["identify"],
+            ConfirmLoginState::CreateSession(..) => vec!["identify"],
         }
     }
 
-    /// Returns the URL to redirect the user to for login
-    pub fn make_login_url(&self, client_id: &str) -> String {
+    /// Returns the ``/create-login`` URL for this state, used for links posted by the bot
+    ///
+    /// The state is only signed once the link is opened so links do not expire
+    pub fn create_login_url(&self) -> String {
         format!(
-            "https://discord.com/api/oauth2/authorize?client_id={}&redirect_uri={}/confirm-login&scope={}&state={}&response_type=code",
-            client_id,
+            "{}/create-login?state={}",
             crate::config::CONFIG.persepolis_domain,
-            self.needed_scopes().join("%20"),
             self
         )
     }
+
+    /// Returns the URL to redirect the user to for login, this is the only place states are signed
+    ///
+    /// ``binding`` ties the state to a cookie, see [`SignedLoginState`]
+    pub fn make_login_url(
+        self,
+        provider: &dyn OAuthProvider,
+        client_id: &str,
+        binding: Option<&str>,
+    ) -> String {
+        let signed = SignedLoginState::new(self, binding);
+
+        provider.authorize_url(
+            client_id,
+            &signed.state.needed_scopes(),
+            &signed.to_string(),
+        )
+    }
+}
+
+/// A [`ConfirmLoginState`] signed by persepolis
+///
+/// Serializes to ``<state>.<nonce>.<issued at>.<binding>.<signature>``. The binding is a hash of a
+/// cookie set when the login was started (or ``-`` if unbound) so a state can only be completed
+/// by the browser that started it
+pub struct SignedLoginState {
+    pub state: ConfirmLoginState,
+    pub nonce: String,
+    pub issued_at: i64,
+    pub binding: Option<String>,
+}
+
+impl SignedLoginState {
+    fn new(state: ConfirmLoginState, binding: Option<&str>) -> Self {
+        Self {
+            state,
+            nonce: botox::crypto::gen_random(24),
+            issued_at: chrono::Utc::now().timestamp(),
+            binding: binding.map(hash_binding),
+        }
+    }
+
+    /// Everything but the signature
+    fn unsigned(&self) -> String {
+        format!(
+            "{}.{}.{}.{}",
+            self.state,
+            self.nonce,
+            self.issued_at,
+            self.binding.as_deref().unwrap_or("-")
+        )
+    }
+
+    fn sign(unsigned: &str) -> ring::hmac::Tag {
+        let key = ring::hmac::Key::new(
+            ring::hmac::HMAC_SHA256,
+            crate::config::CONFIG.session_secret.as_bytes(),
+        );
+
+        ring::hmac::sign(&key, format!("login_state:{}", unsigned).as_bytes())
+    }
+
+    /// Verifies a signed state, checking its signature, age and cookie binding
+    ///
+    /// This does not check whether the state was already used
+    pub fn verify(s: &str, cookie: Option<&str>) -> Result<Self, crate::Error> {
+        let (unsigned, signature) = s.rsplit_once('.').ok_or("Invalid state")?;
+
+        let signature = data_encoding::HEXLOWER
+            .decode(signature.as_bytes())
+            .map_err(|_| "Invalid state")?;
+
+        ring::constant_time::verify_slices_are_equal(
+            Self::sign(unsigned).as_ref(),
+            &signature,
+        )
+        .map_err(|_| "Invalid state signature")?;
+
+        let mut split = unsigned.rsplitn(4, '.');
+
+        let (Some(binding), Some(issued_at), Some(nonce), Some(state)) =
+            (split.next(), split.next(), split.next(), split.next())
+        else {
+            return Err("Invalid state".into());
+        };
+
+        let issued_at = issued_at.parse::<i64>()?;
+
+        if issued_at + LOGIN_STATE_TTL_SECS < chrono::Utc::now().timestamp() {
+            return Err("This login link has expired, please try again".into());
+        }
+
+        let binding = (binding != "-").then(|| binding.to_string());
+
+        if let Some(binding) = &binding {
+            let matches = cookie.is_some_and(|c| {
+                ring::constant_time::verify_slices_are_equal(
+                    hash_binding(c).as_bytes(),
+                    binding.as_bytes(),
+                )
+                .is_ok()
+            });
+
+            if !matches {
+                return Err("This login was started in another browser, please try again".into());
+            }
+        }
+
+        Ok(Self {
+            state: ConfirmLoginState::from_str(state)?,
+            nonce: nonce.to_string(),
+            issued_at,
+            binding,
+        })
+    }
+}
+
+impl Display for SignedLoginState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let unsigned = self.unsigned();
+
+        write!(
+            f,
+            "{}.{}",
+            unsigned,
+            data_encoding::HEXLOWER.encode(Self::sign(&unsigned).as_ref())
+        )
+    }
+}
+
+/// Nonces of signed login states that have been used, so each state can only be used once
+#[derive(Default)]
+pub struct UsedLoginNonces {
+    /// Nonce to when its state was issued, kept until the state expires
+    used: Mutex<HashMap<String, i64>>,
+}
+
+impl UsedLoginNonces {
+    /// Marks the nonce of a state as used, returns false if it already was
+    pub fn mark_used(&self, state: &SignedLoginState) -> bool {
+        let now = chrono::Utc::now().timestamp();
+        let mut used = self.used.lock().unwrap_or_else(|e| e.into_inner());
+
+        used.retain(|_, issued_at| *issued_at + LOGIN_STATE_TTL_SECS >= now);
+
+        used.insert(state.nonce.clone(), state.issued_at).is_none()
+    }
+}
+
+/// Hashes the cookie a login state is bound to
+fn hash_binding(cookie: &str) -> String {
+    let hash = ring::digest::digest(&ring::digest::SHA256, cookie.as_bytes());
+
+    data_encoding::HEXLOWER.encode(&hash.as_ref()[..16])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::mock_oauth::MockProvider;
+
+    const REDIRECT: &str = "https://panel.infinitybots.gg/login?a.b=c.d";
+    const CODE_CHALLENGE: &str = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+
+    fn error(s: &str, cookie: Option<&str>) -> String {
+        SignedLoginState::verify(s, cookie)
+            .err()
+            .expect("state should be rejected")
+            .to_string()
+    }
+
+    /// Signs a state as if it was issued ``age`` seconds ago
+    fn signed(age: i64, binding: Option<&str>) -> String {
+        SignedLoginState {
+            state: ConfirmLoginState::CreateSession(REDIRECT.to_string(), None),
+            nonce: botox::crypto::gen_random(24),
+            issued_at: chrono::Utc::now().timestamp() - age,
+            binding: binding.map(hash_binding),
+        }
+        .to_string()
+    }
+
+    #[test]
+    fn login_url_round_trips() {
+        let url = ConfirmLoginState::CreateSession(
+            REDIRECT.to_string(),
+            Some(CODE_CHALLENGE.to_string()),
+        )
+        .make_login_url(&MockProvider::default(), "client", Some("cookie"));
+
+        let url = reqwest::Url::parse(&url).unwrap();
+        let (_, state) = url.query_pairs().find(|(k, _)| k == "state").unwrap();
+
+        // The encoded state contains dots of its own
+        assert!(state.matches('.').count() > 4);
+
+        let verified = SignedLoginState::verify(&state, Some("cookie"))
+            .unwrap_or_else(|e| panic!("state should verify: {}", e));
+
+        match verified.state {
+            ConfirmLoginState::CreateSession(redirect, code_challenge) => {
+                assert_eq!(redirect, REDIRECT);
+                assert_eq!(code_challenge.as_deref(), Some(CODE_CHALLENGE));
+            }
+            _ => panic!("wrong state"),
+        }
+    }
+
+    #[test]
+    fn unbound_states_need_no_cookie() {
+        assert!(SignedLoginState::verify(&signed(0, None), None).is_ok());
+    }
+
+    #[test]
+    fn tampered_states_are_rejected() {
+        let state = signed(0, None);
+
+        // Signature
+        let (unsigned, signature) = state.rsplit_once('.').unwrap();
+        let flipped = if signature.ends_with('0') { '1' } else { '0' };
+        let tampered = format!(
+            "{}.{}{}",
+            unsigned,
+            &signature[..signature.len() - 1],
+            flipped
+        );
+
+        assert_eq!(error(&tampered, None), "Invalid state signature");
+        assert_eq!(error(&format!("{}.zz", unsigned), None), "Invalid state");
+
+        // Signed contents
+        let other = data_encoding::HEXLOWER.encode(b"https://evil.com");
+        let encoded = data_encoding::HEXLOWER.encode(REDIRECT.as_bytes());
+        let tampered = state.replace(&encoded, &other);
+
+        assert_eq!(error(&tampered, None), "Invalid state signature");
+    }
+
+    #[test]
+    fn expired_states_are_rejected() {
+        assert!(SignedLoginState::verify(&signed(LOGIN_STATE_TTL_SECS - 5, None), None).is_ok());
+        assert_eq!(
+            error(&signed(LOGIN_STATE_TTL_SECS + 1, None), None),
+            "This login link has expired, please try again"
+        );
+    }
+
+    #[test]
+    fn bound_states_need_their_cookie() {
+        let state = signed(0, Some("cookie"));
+
+        assert!(SignedLoginState::verify(&state, Some("cookie")).is_ok());
+
+        for cookie in [None, Some("other"), Some("")] {
+            assert_eq!(
+                error(&state, cookie),
+                "This login was started in another browser, please try again"
+            );
+        }
+    }
+
+    #[test]
+    fn nonces_can_only_be_used_once() {
+        let used = UsedLoginNonces::default();
+
+        let state = SignedLoginState::verify(&signed(0, None), None).unwrap();
+        let other = SignedLoginState::verify(&signed(0, None), None).unwrap();
+
+        assert!(used.mark_used(&state));
+        assert!(!used.mark_used(&state));
+        assert!(used.mark_used(&other));
+    }
+
+    #[test]
+    fn used_nonces_are_forgotten_once_their_state_expires() {
+        let used = UsedLoginNonces::default();
+
+        let mut state = SignedLoginState::verify(&signed(0, None), None).unwrap();
+        state.issued_at -= LOGIN_STATE_TTL_SECS + 1;
+
+        assert!(used.mark_used(&state));
+        assert!(used.mark_used(&SignedLoginState::verify(&signed(0, None), None).unwrap()));
+        assert_eq!(used.used.lock().unwrap().len(), 1);
+    }
 }
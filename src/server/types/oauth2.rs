@@ -1,4 +1,7 @@
+use schemars::JsonSchema;
 use serde::Deserialize;
+use serenity::all::UserId;
+use ts_rs::TS;
 
 #[derive(Deserialize)]
 pub struct AccessTokenResponse {
@@ -6,8 +9,16 @@ pub struct AccessTokenResponse {
     pub scope: String,
 }
 
-#[derive(Deserialize)]
+/// Query string the OAuth2 provider redirects back with
+#[derive(Deserialize, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/ConfirmLogin.ts")]
 pub struct ConfirmLogin {
     pub code: String,
     pub state: String,
 }
+
+/// The user an access token belongs to, only the fields persepolis needs
+#[derive(Deserialize)]
+pub struct OAuthUser {
+    pub id: UserId,
+}
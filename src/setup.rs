@@ -25,12 +25,17 @@ pub async fn get_onboarding_id(ctx: &Context<'_>) -> Result<Option<uuid::Uuid>,
     Ok(None)
 }
 
+/// Bots can only create guilds while they are in less than 10 guilds
+pub const MAX_GUILDS: usize = 10;
+
 /// Sets up a guild
 pub async fn setup_guild(ctx: Context<'_>, msg: &mut Message) -> Result<(), Error> {
-    if ctx.serenity_context().cache.guilds().len() >= 10 {
-        return Err(
-            "Creating new guilds can only be done when the bot is in less than 10 guilds".into(),
-        );
+    if ctx.serenity_context().cache.guilds().len() >= MAX_GUILDS {
+        return Err(format!(
+            "Creating new guilds can only be done when the bot is in less than {} guilds",
+            MAX_GUILDS
+        )
+        .into());
     }
 
     let guild = ctx
@@ -52,14 +57,32 @@ pub async fn setup_guild(ctx: Context<'_>, msg: &mut Message) -> Result<(), Erro
         .map_err(|e| "Could not set MFA level:".to_string() + &e.to_string())?;
 
     // Update DB
-    sqlx::query!(
-        "INSERT INTO staff_onboardings (user_id, guild_id) VALUES ($1, $2)",
+    let mut tx = ctx.data().pool.begin().await?;
+
+    let rec = sqlx::query!(
+        "INSERT INTO staff_onboardings (user_id, guild_id) VALUES ($1, $2) RETURNING id",
         ctx.author().id.to_string(),
         guild.id.to_string()
     )
-    .execute(&ctx.data().pool)
+    .fetch_one(&mut *tx)
     .await?;
 
+    crate::history::record(
+        &mut *tx,
+        crate::history::HistoryEntry {
+            onboarding_id: rec.id,
+            user_id: &ctx.author().id.to_string(),
+            from: None,
+            to: Some(crate::states::OnboardState::Pending),
+            event: crate::states::OnboardEvent::Create,
+            actor: &ctx.author().id.to_string(),
+            metadata: json!({ "guild_id": guild.id.to_string() }),
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
     // Edit message embed
     msg.edit(
         &ctx.serenity_context(),
@@ -78,7 +101,7 @@ pub async fn setup_guild(ctx: Context<'_>, msg: &mut Message) -> Result<(), Erro
                     vec![
                         CreateButton::new_link(
                             {
-                                let url = ConfirmLoginState::JoinOnboardingServer(ctx.author().id).make_login_url(&ctx.cache().current_user().id.to_string());
+                                let url = ConfirmLoginState::JoinOnboardingServer(ctx.author().id).create_login_url();
 
                                 url.clone()
                             }
@@ -0,0 +1,267 @@
+use std::collections::{HashMap, HashSet};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::{types::uuid, PgConnection};
+use ts_rs::TS;
+
+use crate::config::QuestionData;
+use crate::questions::QuizQuestion;
+use crate::Error;
+
+/// Number of words in a shingle
+const SHINGLE_SIZE: usize = 3;
+
+/// Number of hash functions in a MinHash signature
+const NUM_HASHES: usize = 64;
+
+/// Number of most similar previous answers kept per question
+const MAX_MATCHES: usize = 3;
+
+/// Answers at least this similar to a previous answer get flagged
+pub const FLAG_THRESHOLD: f64 = 0.6;
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/SimilarityMatch.ts")]
+pub struct SimilarityMatch {
+    pub onboarding_id: String,
+    pub user_id: String,
+    /// Estimated Jaccard similarity of the two answers, from 0 to 1
+    pub similarity: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/AnswerSimilarity.ts")]
+pub struct AnswerSimilarity {
+    pub question: String,
+    /// Most similar previous answers, most similar first
+    pub matches: Vec<SimilarityMatch>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema, TS)]
+#[ts(export, export_to = ".generated/SimilarityReport.ts")]
+pub struct SimilarityReport {
+    pub answers: Vec<AnswerSimilarity>,
+    /// Whether any answer is at least [`FLAG_THRESHOLD`] similar to a previous one
+    pub flagged: bool,
+}
+
+impl SimilarityReport {
+    /// Returns the question and match with the highest similarity, if any
+    pub fn top_match(&self) -> Option<(&str, &SimilarityMatch)> {
+        self.answers
+            .iter()
+            .filter_map(|a| a.matches.first().map(|m| (a.question.as_str(), m)))
+            .max_by(|a, b| a.1.similarity.total_cmp(&b.1.similarity))
+    }
+}
+
+/// Key identifying a question across quizzes, the question bank ID if there is one
+fn question_key(q: &QuizQuestion) -> String {
+    q.id.clone().unwrap_or_else(|| q.question.question.clone())
+}
+
+/// FNV-1a, used instead of the std hasher as signatures are stored and must stay stable
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |h, b| {
+        (h ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// splitmix64 finalizer, derives the hash functions of the signature from one base hash
+fn mix(mut h: u64) -> u64 {
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d049bb133111eb);
+    h ^ (h >> 31)
+}
+
+/// Splits an answer into overlapping word shingles, ignoring case and punctuation
+fn shingles(text: &str) -> HashSet<u64> {
+    let words = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect::<Vec<String>>();
+
+    if words.len() < SHINGLE_SIZE {
+        return HashSet::from([fnv1a(words.join(" ").as_bytes())]);
+    }
+
+    words
+        .windows(SHINGLE_SIZE)
+        .map(|w| fnv1a(w.join(" ").as_bytes()))
+        .collect()
+}
+
+/// Computes the MinHash signature of an answer
+pub fn fingerprint(text: &str) -> Vec<i64> {
+    let shingles = shingles(text);
+
+    (0..NUM_HASHES as u64)
+        .map(|i| {
+            let seed = mix(i.wrapping_add(0x9e3779b97f4a7c15));
+
+            shingles
+                .iter()
+                .map(|s| mix(s ^ seed))
+                .min()
+                .unwrap_or(u64::MAX) as i64
+        })
+        .collect()
+}
+
+/// Estimates the Jaccard similarity of the answers behind two signatures
+pub fn estimate(a: &[i64], b: &[i64]) -> f64 {
+    if a.len() != b.len() || a.is_empty() {
+        return 0.0;
+    }
+
+    a.iter().zip(b).filter(|(x, y)| x == y).count() as f64 / a.len() as f64
+}
+
+/// Compares every short and long answer against previous answers to the same question by
+/// other users, then stores the fingerprints of the answers for future submissions
+pub async fn check(
+    conn: &mut PgConnection,
+    onboarding_id: uuid::Uuid,
+    user_id: &str,
+    questions: &[QuizQuestion],
+    answers: &HashMap<String, String>,
+) -> Result<SimilarityReport, Error> {
+    let mut report = SimilarityReport::default();
+
+    for q in questions {
+        if matches!(q.question.data, QuestionData::MultipleChoice(_)) {
+            continue;
+        }
+
+        let Some(answer) = answers.get(&q.question.question) else {
+            continue;
+        };
+
+        let key = question_key(q);
+        let signature = fingerprint(answer);
+
+        let previous = sqlx::query!(
+            "SELECT onboarding_id, user_id, signature FROM staff_onboarding_answer_fingerprints WHERE question = $1 AND user_id != $2",
+            key,
+            user_id
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let mut matches = previous
+            .into_iter()
+            .map(|p| SimilarityMatch {
+                onboarding_id: p.onboarding_id.hyphenated().to_string(),
+                user_id: p.user_id,
+                similarity: estimate(&signature, &p.signature),
+            })
+            .filter(|m| m.similarity > 0.0)
+            .collect::<Vec<SimilarityMatch>>();
+
+        matches.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+        matches.truncate(MAX_MATCHES);
+
+        if matches
+            .first()
+            .is_some_and(|m| m.similarity >= FLAG_THRESHOLD)
+        {
+            report.flagged = true;
+        }
+
+        if !matches.is_empty() {
+            report.answers.push(AnswerSimilarity {
+                question: q.question.question.clone(),
+                matches,
+            });
+        }
+
+        sqlx::query!(
+            "INSERT INTO staff_onboarding_answer_fingerprints (onboarding_id, user_id, question, signature) VALUES ($1, $2, $3, $4)",
+            onboarding_id,
+            user_id,
+            key,
+            &signature
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ANSWER: &str = "The bot must be tested in the testing server before it is approved, \
+        and any command that fails has to be reported to the owner with the exact error shown";
+
+    #[test]
+    fn identical_answers_are_fully_similar() {
+        assert_eq!(estimate(&fingerprint(ANSWER), &fingerprint(ANSWER)), 1.0);
+    }
+
+    #[test]
+    fn disjoint_answers_are_not_similar() {
+        let other = "Reviewers should always stay polite towards developers even when their \
+            submissions are clearly unfinished or obviously copied from a template";
+
+        assert!(estimate(&fingerprint(ANSWER), &fingerprint(other)) < 0.1);
+    }
+
+    #[test]
+    fn partially_copied_answers_are_partially_similar() {
+        let copied = format!("{} and then wait for a reply before denying it", ANSWER);
+        let similarity = estimate(&fingerprint(ANSWER), &fingerprint(&copied));
+
+        assert!(similarity > 0.5 && similarity < 1.0, "{}", similarity);
+    }
+
+    #[test]
+    fn answers_shorter_than_a_shingle_are_one_shingle() {
+        assert_eq!(shingles("just two").len(), 1);
+        assert_eq!(shingles("").len(), 1);
+
+        assert_eq!(
+            estimate(&fingerprint("just two"), &fingerprint("Just  two!")),
+            1.0
+        );
+        assert_eq!(
+            estimate(&fingerprint("just two"), &fingerprint("only two")),
+            0.0
+        );
+    }
+
+    #[test]
+    fn case_whitespace_and_punctuation_are_ignored() {
+        let normalised = "the bot must be tested";
+        let messy = "  The BOT,\tmust\n\nbe tested!!! ";
+
+        assert_eq!(shingles(normalised), shingles(messy));
+        assert_eq!(fingerprint(normalised), fingerprint(messy));
+    }
+
+    #[test]
+    fn word_order_matters() {
+        assert_ne!(
+            shingles("the bot must be tested"),
+            shingles("tested be must bot the")
+        );
+    }
+
+    #[test]
+    fn fingerprints_are_stable() {
+        // Signatures are stored, so the hashing must not change between releases
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fingerprint(ANSWER).len(), NUM_HASHES);
+    }
+
+    #[test]
+    fn mismatched_signatures_are_not_similar() {
+        assert_eq!(estimate(&[], &[]), 0.0);
+        assert_eq!(estimate(&[1, 2], &[1]), 0.0);
+    }
+}
@@ -1,6 +1,14 @@
+use std::fmt::{Display, Formatter};
+
+use sqlx::{types::uuid, Connection, PgConnection};
 use strum_macros::{Display, EnumString};
 
-#[derive(PartialEq, Display, EnumString)]
+use crate::Error;
+
+/// Minimum length of the reason given when approving/denying the test bot
+pub const MIN_VERDICT_REASON_LEN: usize = 30;
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Display, EnumString)]
 #[strum(serialize_all = "snake_case")]
 pub enum OnboardState {
     Pending, // Needed
@@ -11,4 +19,282 @@ pub enum OnboardState {
     PendingManagerReview, // Needed
     Denied,               // Needed
     Completed,            // Needed
+    /// Not submitted in time
+    Expired,
+}
+
+/// Something that happened to an onboarding which may move it to a new state
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Display, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum OnboardEvent {
+    /// Trainee ran ``queue`` for the first time
+    Queue,
+    /// Trainee reminded the (fake) reviewer who has claimed the test bot
+    RemindReviewer,
+    /// Trainee force claimed the test bot
+    ForceClaim,
+    /// Trainee approved the test bot
+    ApproveBot,
+    /// Trainee denied the test bot
+    DenyBot,
+    /// Trainee submitted the quiz
+    SubmitQuiz,
+    /// A manager approved the onboarding
+    ManagerApprove,
+    /// A manager denied the onboarding
+    ManagerDeny,
+    /// The onboarding was created. Recorded in history only, never a transition
+    Create,
+    /// The onboarding was voided by a manager. Recorded in history only, never a transition
+    Void,
+    /// The onboarding was not submitted in time
+    Expire,
+}
+
+/// Extra information guards may inspect before allowing a transition
+pub struct TransitionContext<'a> {
+    /// User ID of whoever caused the transition or [`crate::history::SYSTEM_ACTOR`]
+    pub actor: &'a str,
+    /// Reason given by the actor, if any. Also stored in the history
+    pub reason: Option<&'a str>,
+    /// Extra data to store alongside the transition in the history
+    pub metadata: serde_json::Value,
+    /// Whether the actor asked to skip checking that grading is complete
+    pub skip_grading: bool,
+    /// Whether every question of the onboarding that needs manual grading has been graded
+    pub grading_complete: bool,
+}
+
+impl<'a> TransitionContext<'a> {
+    pub fn new(actor: &'a str) -> Self {
+        Self {
+            actor,
+            reason: None,
+            metadata: serde_json::json!({}),
+            skip_grading: false,
+            grading_complete: false,
+        }
+    }
+}
+
+/// A guard is run before a transition is persisted and may reject it with a reason
+pub type Guard = fn(&TransitionContext) -> Result<(), &'static str>;
+
+pub struct Transition {
+    pub from: OnboardState,
+    pub event: OnboardEvent,
+    pub to: OnboardState,
+    pub guard: Option<Guard>,
+}
+
+/// Every legal transition of an onboarding. Anything not in this table is rejected
+pub const TRANSITIONS: &[Transition] = &[
+    Transition {
+        from: OnboardState::Pending,
+        event: OnboardEvent::Queue,
+        to: OnboardState::Started,
+        guard: None,
+    },
+    Transition {
+        from: OnboardState::Started,
+        event: OnboardEvent::RemindReviewer,
+        to: OnboardState::QueueRemindedReviewer,
+        guard: None,
+    },
+    Transition {
+        from: OnboardState::QueueRemindedReviewer,
+        event: OnboardEvent::ForceClaim,
+        to: OnboardState::Claimed,
+        guard: None,
+    },
+    Transition {
+        from: OnboardState::Claimed,
+        event: OnboardEvent::ApproveBot,
+        to: OnboardState::InQuiz,
+        guard: Some(require_reason),
+    },
+    Transition {
+        from: OnboardState::Claimed,
+        event: OnboardEvent::DenyBot,
+        to: OnboardState::InQuiz,
+        guard: Some(require_reason),
+    },
+    Transition {
+        from: OnboardState::InQuiz,
+        event: OnboardEvent::SubmitQuiz,
+        to: OnboardState::PendingManagerReview,
+        guard: None,
+    },
+    Transition {
+        from: OnboardState::PendingManagerReview,
+        event: OnboardEvent::ManagerApprove,
+        to: OnboardState::Completed,
+        guard: Some(require_grading),
+    },
+    Transition {
+        from: OnboardState::PendingManagerReview,
+        event: OnboardEvent::ManagerDeny,
+        to: OnboardState::Denied,
+        guard: Some(require_grading),
+    },
+    Transition {
+        from: OnboardState::Pending,
+        event: OnboardEvent::Expire,
+        to: OnboardState::Expired,
+        guard: Some(require_expiry_reason),
+    },
+    Transition {
+        from: OnboardState::Started,
+        event: OnboardEvent::Expire,
+        to: OnboardState::Expired,
+        guard: Some(require_expiry_reason),
+    },
+    Transition {
+        from: OnboardState::QueueRemindedReviewer,
+        event: OnboardEvent::Expire,
+        to: OnboardState::Expired,
+        guard: Some(require_expiry_reason),
+    },
+    Transition {
+        from: OnboardState::Claimed,
+        event: OnboardEvent::Expire,
+        to: OnboardState::Expired,
+        guard: Some(require_expiry_reason),
+    },
+    Transition {
+        from: OnboardState::InQuiz,
+        event: OnboardEvent::Expire,
+        to: OnboardState::Expired,
+        guard: Some(require_expiry_reason),
+    },
+];
+
+fn require_reason(ctx: &TransitionContext) -> Result<(), &'static str> {
+    match ctx.reason {
+        Some(reason) if reason.len() >= MIN_VERDICT_REASON_LEN => Ok(()),
+        _ => Err("Please provide a reason that is at least 30 characters long!"),
+    }
+}
+
+fn require_expiry_reason(ctx: &TransitionContext) -> Result<(), &'static str> {
+    match ctx.reason {
+        Some(reason) if !reason.is_empty() => Ok(()),
+        _ => Err("Expiring an onboarding requires a reason"),
+    }
+}
+
+fn require_grading(ctx: &TransitionContext) -> Result<(), &'static str> {
+    if ctx.skip_grading || ctx.grading_complete {
+        Ok(())
+    } else {
+        Err("Grading of this onboarding is not complete yet. Grade it first or use skip_grading if this is really needed")
+    }
+}
+
+#[derive(Debug)]
+pub enum TransitionError {
+    /// There is no transition for this event from the given state
+    Illegal {
+        from: OnboardState,
+        event: OnboardEvent,
+    },
+    /// The guard of the transition rejected it
+    GuardFailed(&'static str),
+    /// The onboarding was not in the expected state when persisting (it was changed concurrently or voided)
+    Conflict { expected: OnboardState },
+}
+
+impl Display for TransitionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransitionError::Illegal { from, event } => {
+                write!(f, "Cannot {} an onboarding that is in state {}", event, from)
+            }
+            TransitionError::GuardFailed(reason) => write!(f, "{}", reason),
+            TransitionError::Conflict { expected } => write!(
+                f,
+                "This onboarding is no longer in state {}, it may have been changed by another command. Please try again",
+                expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TransitionError {}
+
+/// States an onboarding can expire from, that is every state before it is submitted
+pub fn expirable_states() -> Vec<OnboardState> {
+    TRANSITIONS
+        .iter()
+        .filter(|t| t.event == OnboardEvent::Expire)
+        .map(|t| t.from)
+        .collect()
+}
+
+/// Looks up the transition for an event from a state
+pub fn lookup(
+    from: OnboardState,
+    event: OnboardEvent,
+) -> Result<&'static Transition, TransitionError> {
+    TRANSITIONS
+        .iter()
+        .find(|t| t.from == from && t.event == event)
+        .ok_or(TransitionError::Illegal { from, event })
+}
+
+/// Validates and persists a transition, returning the new state
+///
+/// The update is a compare-and-swap on the previous state so two commands racing on the
+/// same onboarding cannot both advance it. The transition is recorded in the history
+/// in the same transaction
+pub async fn transition(
+    conn: &mut PgConnection,
+    onboarding_id: uuid::Uuid,
+    from: OnboardState,
+    event: OnboardEvent,
+    ctx: &TransitionContext<'_>,
+) -> Result<OnboardState, Error> {
+    let t = lookup(from, event)?;
+
+    if let Some(guard) = t.guard {
+        guard(ctx).map_err(TransitionError::GuardFailed)?;
+    }
+
+    let mut tx = conn.begin().await?;
+
+    let Some(rec) = sqlx::query!(
+        "UPDATE staff_onboardings SET state = $1 WHERE id = $2 AND state = $3 AND void = false RETURNING user_id",
+        t.to.to_string(),
+        onboarding_id,
+        from.to_string()
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Err(TransitionError::Conflict { expected: from }.into());
+    };
+
+    let mut metadata = ctx.metadata.clone();
+
+    if let (Some(reason), Some(obj)) = (ctx.reason, metadata.as_object_mut()) {
+        obj.insert("reason".to_string(), reason.into());
+    }
+
+    crate::history::record(
+        &mut *tx,
+        crate::history::HistoryEntry {
+            onboarding_id,
+            user_id: &rec.user_id,
+            from: Some(from),
+            to: Some(t.to),
+            event,
+            actor: ctx.actor,
+            metadata,
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(t.to)
 }
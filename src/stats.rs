@@ -1,5 +1,8 @@
 use poise::{serenity_prelude::CreateEmbed, CreateReply};
 
+use crate::setup::MAX_GUILDS;
+use crate::states::OnboardState;
+
 type Error = crate::Error;
 type Context<'a> = crate::Context<'a>;
 
@@ -12,8 +15,54 @@ pub const BUILD_CPU: &str = env!("VERGEN_SYSINFO_CPU_BRAND");
 pub const CARGO_PROFILE: &str = env!("VERGEN_CARGO_PROFILE");
 pub const RUSTC_VERSION: &str = env!("VERGEN_RUSTC_SEMVER");
 
+/// Formats a duration as days, hours, minutes and seconds
+fn format_uptime(secs: u64) -> String {
+    format!(
+        "{}d {}h {}m {}s",
+        secs / 86400,
+        (secs % 86400) / 3600,
+        (secs % 3600) / 60,
+        secs % 60
+    )
+}
+
 #[poise::command(category = "Stats", prefix_command, slash_command, user_cooldown = 1)]
 pub async fn stats(ctx: Context<'_>) -> Result<(), Error> {
+    let data = ctx.data();
+
+    let (gateway_connected, gateway_latency) = crate::metrics::gateway_status().await;
+
+    let gateway = match gateway_latency {
+        Some(latency) => format!("{}ms", latency.as_millis()),
+        None if gateway_connected => "Connected, no heartbeat yet".to_string(),
+        None => "Not connected".to_string(),
+    };
+
+    let pool_size = data.pool.size();
+    let pool_idle = data.pool.num_idle();
+
+    let requests = crate::metrics::METRICS.request_counts();
+    let total_requests: u64 = requests.iter().map(|(_, count)| count).sum();
+
+    let top_requests = requests
+        .iter()
+        .take(5)
+        .map(|(route, count)| format!("`{}`: {}", route, count))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let by_state = crate::history::count_by_state(&data.pool)
+        .await?
+        .into_iter()
+        .map(|(state, count)| format!("`{}`: {}", state, count))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let (completed_week, completed_month) =
+        crate::history::recent_transitions_to(&data.pool, OnboardState::Completed).await?;
+    let (denied_week, denied_month) =
+        crate::history::recent_transitions_to(&data.pool, OnboardState::Denied).await?;
+
     let msg = CreateReply::default().embed(
         CreateEmbed::default()
             .title("Bot Stats")
@@ -26,7 +75,59 @@ pub async fn stats(ctx: Context<'_>) -> Result<(), Error> {
             )
             .field("Commit Message", GIT_COMMIT_MSG, true)
             .field("Built On", BUILD_CPU, true)
-            .field("Cargo Profile", CARGO_PROFILE, true),
+            .field("Cargo Profile", CARGO_PROFILE, true)
+            .field(
+                "Uptime",
+                format_uptime(crate::metrics::METRICS.started_at.elapsed().as_secs()),
+                true,
+            )
+            .field("Gateway Latency", gateway, true)
+            .field(
+                "Guilds",
+                format!(
+                    "{}/{} (new onboarding guilds can be created below {})",
+                    ctx.serenity_context().cache.guild_count(),
+                    MAX_GUILDS,
+                    MAX_GUILDS
+                ),
+                true,
+            )
+            .field(
+                "Database Pool",
+                format!(
+                    "{} in use, {} idle, {} max",
+                    (pool_size as usize).saturating_sub(pool_idle),
+                    pool_idle,
+                    data.pool.options().get_max_connections()
+                ),
+                true,
+            )
+            .field(
+                "RPC Requests",
+                if top_requests.is_empty() {
+                    "None yet".to_string()
+                } else {
+                    format!("{} total\n{}", total_requests, top_requests)
+                },
+                true,
+            )
+            .field(
+                "Onboardings",
+                if by_state.is_empty() {
+                    "None".to_string()
+                } else {
+                    by_state
+                },
+                true,
+            )
+            .field(
+                "Completed / Denied",
+                format!(
+                    "Last 7 days: {} / {}\nLast 30 days: {} / {}",
+                    completed_week, denied_week, completed_month, denied_month
+                ),
+                true,
+            ),
     );
 
     ctx.send(msg).await?;